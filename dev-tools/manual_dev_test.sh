@@ -12,3 +12,8 @@ curl "$API_BASE_URL/login" --cacert "$CERT_PATH" -H "Content-Type: application/j
 
 curl "$API_BASE_URL/signup" --cacert "$CERT_PATH" -H "Content-Type: application/json" \
 -d '{"username":"testuser", "password":"testpass", "captcha_id":"00000000-0000-0000-0000-000000000000", "captcha_answer":"123456"}'
+
+curl "$API_BASE_URL/token/refresh" --cacert "$CERT_PATH" -H "Content-Type: application/json" \
+-d '{"refresh_token":"fake-refresh-token:testuser"}'
+
+curl -X POST "$API_BASE_URL/logout" --cacert "$CERT_PATH" -H "Authorization: Bearer fake-access-token:testuser"
\ No newline at end of file
@@ -0,0 +1,27 @@
+#!/usr/bin/bash -x
+
+# Manual test for the redis captcha backend against a locally started redis-server.
+# Start the server with `[captcha] backend = "redis"` first:
+#   redis-server --port 6379 --save '' &
+#   cargo run --bin server_oxide -- --settings=<settings with backend = "redis">
+
+HOST=127.0.0.1:8443
+API_BASE_URL=https://$HOST/api/v1
+
+CERT_PATH=../certs/dev_cert.pem
+KEY_PREFIX="server_oxide:captcha:"
+
+CAPTCHA_ID=$(curl -s "$API_BASE_URL/captcha" --cacert "$CERT_PATH" | sed -E 's/.*"id":"([^"]+)".*/\1/')
+
+# The answer is stored with a native TTL
+redis-cli TTL "$KEY_PREFIX$CAPTCHA_ID"
+ANSWER=$(redis-cli GET "$KEY_PREFIX$CAPTCHA_ID")
+
+curl "$API_BASE_URL/signup" --cacert "$CERT_PATH" -H "Content-Type: application/json" \
+-d "{\"username\":\"testuser\", \"password\":\"testpass\", \"captcha_id\":\"$CAPTCHA_ID\", \"captcha_answer\":\"$ANSWER\"}"
+
+# The answer was consumed by the first attempt, so this one fails with invalid_captcha
+curl "$API_BASE_URL/signup" --cacert "$CERT_PATH" -H "Content-Type: application/json" \
+-d "{\"username\":\"testuser\", \"password\":\"testpass\", \"captcha_id\":\"$CAPTCHA_ID\", \"captcha_answer\":\"$ANSWER\"}"
+
+redis-cli EXISTS "$KEY_PREFIX$CAPTCHA_ID"
\ No newline at end of file
@@ -1,6 +1,10 @@
+use std::convert::Infallible;
+use serde::Serialize;
 use thiserror::Error;
-use tracing::warn;
-use warp::reject;
+use tracing::{debug, warn};
+use warp::http::StatusCode;
+use warp::{reject, Rejection, Reply};
+use crate::attachment::AttachmentError;
 use crate::auth::AuthError;
 use crate::captcha::CaptchaError;
 
@@ -14,12 +18,127 @@ pub enum ApiError {
     UsernameTaken,
     #[error("Token is not valid")]
     InvalidToken,
+    #[error("Token expired")]
+    TokenExpired,
+    #[error("Refresh token is not valid")]
+    InvalidRefreshToken,
+    #[error("Refresh token expired")]
+    RefreshTokenExpired,
+    #[error("Refresh token has already been used")]
+    RefreshTokenReused,
+    #[error("Attachment is empty")]
+    EmptyAttachment,
+    #[error("Attachments may be at most {0} bytes")]
+    AttachmentTooLarge(u64),
+    #[error("Upload quota of {0} bytes exceeded")]
+    QuotaExceeded(u64),
+    #[error("Image could not be read")]
+    MalformedImage,
+    #[error("Attachment not found")]
+    AttachmentNotFound,
+    #[error("Not a member of the conversation")]
+    NotAMember,
     #[error("Internal error")]
     InternalError,
 }
 
 impl reject::Reject for ApiError {}
 
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidCaptcha | ApiError::EmptyAttachment | ApiError::MalformedImage => StatusCode::BAD_REQUEST,
+            ApiError::InvalidCredentials
+            | ApiError::InvalidToken
+            | ApiError::TokenExpired
+            | ApiError::InvalidRefreshToken
+            | ApiError::RefreshTokenExpired
+            | ApiError::RefreshTokenReused => StatusCode::UNAUTHORIZED,
+            ApiError::UsernameTaken => StatusCode::CONFLICT,
+            ApiError::AttachmentTooLarge(_) | ApiError::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::AttachmentNotFound => StatusCode::NOT_FOUND,
+            ApiError::NotAMember => StatusCode::FORBIDDEN,
+            ApiError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidCaptcha => "invalid_captcha",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::UsernameTaken => "username_taken",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::TokenExpired => "token_expired",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::RefreshTokenExpired => "refresh_token_expired",
+            ApiError::RefreshTokenReused => "refresh_token_reused",
+            ApiError::EmptyAttachment => "empty_attachment",
+            ApiError::AttachmentTooLarge(_) => "attachment_too_large",
+            ApiError::QuotaExceeded(_) => "quota_exceeded",
+            ApiError::MalformedImage => "malformed_image",
+            ApiError::AttachmentNotFound => "attachment_not_found",
+            ApiError::NotAMember => "not_a_member",
+            ApiError::InternalError => "internal_error",
+        }
+    }
+}
+
+/// The JSON body of every error response.
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: String,
+    /// Also logged with the rejection, so a client report can be matched to server logs.
+    pub request_id: uuid::Uuid,
+}
+
+/// Turns every rejection into an `ErrorResponse` with a matching status code.
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, code, message) = if let Some(e) = rejection.find::<ApiError>() {
+        (e.status(), e.code(), e.to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "invalid_body", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MissingHeader>() {
+        if e.name().eq_ignore_ascii_case(warp::http::header::AUTHORIZATION.as_str()) {
+            (StatusCode::UNAUTHORIZED, "missing_authorization", e.to_string())
+        } else {
+            (StatusCode::BAD_REQUEST, "missing_header", e.to_string())
+        }
+    } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, "invalid_header", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "invalid_query", e.to_string())
+    } else if let Some(e) = rejection.find::<warp::ws::MissingConnectionUpgrade>() {
+        (StatusCode::BAD_REQUEST, "websocket_upgrade_required", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, "length_required", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", e.to_string())
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "Not found".to_string())
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", ApiError::InternalError.to_string())
+    };
+
+    let request_id = uuid::Uuid::new_v4();
+    if status.is_server_error() {
+        warn!(%request_id, ?rejection, "Request failed");
+    } else {
+        debug!(%request_id, ?rejection, "Request rejected");
+    }
+
+    let body = ErrorResponse {
+        code,
+        message,
+        request_id,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
 pub fn map_captcha_error_to_api_error(e: CaptchaError) -> ApiError {
     match e {
         CaptchaError::Mismatch => ApiError::InvalidCaptcha,
@@ -35,15 +154,29 @@ pub fn map_auth_error_to_api_error(e: AuthError) -> ApiError {
     match e {
         AuthError::InvalidCredentials => ApiError::InvalidCredentials,
         AuthError::UsernameTaken => ApiError::UsernameTaken,
-        // These error variants are grouped under InvalidToken for now.
-        // Will need finer-grained mapping when JWT support is implemented.
-        AuthError::InvalidToken
-        | AuthError::TokenExpired
-        | AuthError::InvalidRefreshToken
-        | AuthError::RefreshTokenExpired => ApiError::InvalidToken,
+        AuthError::InvalidToken => ApiError::InvalidToken,
+        AuthError::TokenExpired => ApiError::TokenExpired,
+        AuthError::InvalidRefreshToken => ApiError::InvalidRefreshToken,
+        AuthError::RefreshTokenExpired => ApiError::RefreshTokenExpired,
+        AuthError::RefreshTokenReused => ApiError::RefreshTokenReused,
         AuthError::InternalError(e) => {
             warn!("Internal auth error: {}", e);
             ApiError::InternalError
         }
     }
+}
+
+pub fn map_attachment_error_to_api_error(e: AttachmentError) -> ApiError {
+    match e {
+        AttachmentError::Empty => ApiError::EmptyAttachment,
+        AttachmentError::TooLarge(max_size) => ApiError::AttachmentTooLarge(max_size),
+        AttachmentError::QuotaExceeded(quota) => ApiError::QuotaExceeded(quota),
+        AttachmentError::MalformedImage => ApiError::MalformedImage,
+        AttachmentError::NotFound => ApiError::AttachmentNotFound,
+        AttachmentError::NotAMember => ApiError::NotAMember,
+        AttachmentError::AlreadyPosted | AttachmentError::InternalError(_) => {
+            warn!("Internal attachment error: {}", e);
+            ApiError::InternalError
+        }
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use super::error::*;
+use crate::attachment::*;
 use crate::auth::*;
 use crate::captcha::*;
 use crate::logger::*;
@@ -8,7 +9,7 @@ use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use warp::{self, reject};
-use crate::domain::UserId;
+use crate::domain::{AttachmentId, UserId};
 
 /// TODO: This is currently a God File to help us move fast.
 /// Refactor and tidy up when the feature set is more stable.
@@ -120,6 +121,155 @@ pub async fn signup(
 
     Ok(warp::reply::json(&SignupResponse))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefreshResponse {
+    pub auth_tokens: AuthTokens,
+}
+
+pub async fn refresh_token(
+    body: RefreshRequest,
+    auth_service: Arc<dyn AuthService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let auth_tokens = auth_service
+        .refresh_token(&body.refresh_token)
+        .await
+        .map_err(map_auth_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    Ok(warp::reply::json(&RefreshResponse { auth_tokens }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogoutResponse;
+
+pub async fn logout(
+    token: String,
+    auth_service: Arc<dyn AuthService>,
+    chat_service: Arc<dyn ChatService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let user_id = auth_service
+        .logout(&token)
+        .await
+        .map_err(map_auth_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    // Chat connections aren't tied to a session, so all of the user's sockets are closed.
+    // Other devices reconnect with their still valid tokens.
+    if let Err(e) = chat_service.disconnect_user(&user_id).await {
+        warn!("Failed to disconnect chat of {:?}: {}", user_id, e);
+    }
+
+    Ok(warp::reply::json(&LogoutResponse))
+}
+
+pub async fn logout_all(
+    user_id: UserId,
+    auth_service: Arc<dyn AuthService>,
+    chat_service: Arc<dyn ChatService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    auth_service
+        .logout_all(&user_id)
+        .await
+        .map_err(map_auth_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    if let Err(e) = chat_service.disconnect_user(&user_id).await {
+        warn!("Failed to disconnect chat of {:?}: {}", user_id, e);
+    }
+
+    Ok(warp::reply::json(&LogoutResponse))
+}
+
+pub async fn issue_ws_ticket(
+    user_id: UserId,
+    auth_service: Arc<dyn AuthService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let ws_ticket = auth_service
+        .issue_ws_ticket(&user_id)
+        .await
+        .map_err(map_auth_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    Ok(warp::reply::json(&ws_ticket))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    pub name: Option<String>,
+}
+
+/// The body is the raw file content, its type is sniffed from the bytes.
+pub async fn upload_attachment(
+    user_id: UserId,
+    query: UploadQuery,
+    body: warp::hyper::body::Bytes,
+    attachment_service: Arc<dyn AttachmentService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let attachment = attachment_service
+        .upload(&user_id, query.name, &body)
+        .await
+        .map_err(map_attachment_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    Ok(warp::reply::json(&AttachmentInfo::from(&attachment)))
+}
+
+pub async fn download_attachment(
+    id: uuid::Uuid,
+    user_id: UserId,
+    attachment_service: Arc<dyn AttachmentService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (attachment, bytes) = attachment_service
+        .download(&user_id, AttachmentId(id))
+        .await
+        .map_err(map_attachment_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    // Only images are shown inline, everything else is saved by the browser.
+    let disposition = if attachment.content_type.starts_with("image/") { "inline" } else { "attachment" };
+    let disposition = match &attachment.name {
+        Some(name) => format!("{}; filename=\"{}\"", disposition, name),
+        None => disposition.to_string(),
+    };
+    warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, attachment.content_type)
+        .header(warp::http::header::CONTENT_DISPOSITION, disposition)
+        .header(warp::http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(bytes)
+        .map_err(|e| {
+            warn!("Failed to build attachment response: {}", e);
+            reject::custom(ApiError::InternalError)
+        })
+}
+
+pub async fn download_thumbnail(
+    id: uuid::Uuid,
+    size: u32,
+    user_id: UserId,
+    attachment_service: Arc<dyn AttachmentService>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (thumbnail, bytes) = attachment_service
+        .download_thumbnail(&user_id, AttachmentId(id), size)
+        .await
+        .map_err(map_attachment_error_to_api_error)
+        .map_err(reject::custom)?;
+
+    warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, thumbnail.content_type)
+        .header(warp::http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(bytes)
+        .map_err(|e| {
+            warn!("Failed to build thumbnail response: {}", e);
+            reject::custom(ApiError::InternalError)
+        })
+}
+
 pub async fn join_chat(
     socket: warp::ws::WebSocket,
     user_id: UserId,
@@ -2,4 +2,5 @@ mod error;
 mod handler;
 mod router;
 
+pub use error::{ApiError, ErrorResponse, handle_rejection};
 pub use router::routes;
\ No newline at end of file
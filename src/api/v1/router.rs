@@ -3,49 +3,131 @@ use super::handler;
 use crate::auth::*;
 use crate::chat::ChatService;
 use crate::server::*;
+use serde::Deserialize;
 use std::convert::Infallible;
 use std::sync::Arc;
-use warp::{http, reject, Filter};
+use warp::{http, reject, Filter, Reply};
 use crate::domain::UserId;
 
+/// Subprotocol a browser offers together with its access token,
+/// e.g. `new WebSocket(url, ["bearer", token])`. The server echoes it back.
+const BEARER_PROTOCOL: &str = "bearer";
+
 pub fn routes(
     server: Server,
-) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let captcha = warp::get()
-        .and(warp::path("captcha"))
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    let captcha = warp::path("captcha")
         .and(warp::path::end())
+        .and(warp::get())
         .and(with(server.captcha_service.clone()))
         .and_then(handler::generate_captcha);
 
-    let login = warp::post()
-        .and(warp::path("login"))
+    let login = warp::path("login")
         .and(warp::path::end())
+        .and(warp::post())
         .and(warp::body::json())
         .and(with(server.auth_service.clone()))
         .and(with(server.captcha_service.clone()))
         .and_then(handler::login);
 
-    let signup = warp::post()
-        .and(warp::path("signup"))
+    let signup = warp::path("signup")
         .and(warp::path::end())
+        .and(warp::post())
         .and(warp::body::json())
         .and(with(server.auth_service.clone()))
         .and(with(server.captcha_service.clone()))
         .and_then(handler::signup);
 
-    let chat = warp::get()
-        .and(warp::path("chat"))
+    let refresh = warp::path("token")
+        .and(warp::path("refresh"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with(server.auth_service.clone()))
+        .and_then(handler::refresh_token);
+
+    let logout = warp::path("logout")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_bearer_token())
+        .and(with(server.auth_service.clone()))
+        .and(with(server.chat_service.clone()))
+        .and_then(handler::logout);
+
+    let logout_all = warp::path("logout")
+        .and(warp::path("all"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_verification(server.auth_service.clone()))
+        .and(with(server.auth_service.clone()))
+        .and(with(server.chat_service.clone()))
+        .and_then(handler::logout_all);
+
+    let ws_ticket = warp::path("chat")
+        .and(warp::path("ticket"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_verification(server.auth_service.clone()))
+        .and(with(server.auth_service.clone()))
+        .and_then(handler::issue_ws_ticket);
+
+    let upload_attachment = warp::path("attachments")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_verification(server.auth_service.clone()))
+        .and(warp::query::<handler::UploadQuery>())
+        .and(warp::body::content_length_limit(server.attachment_service.max_size()))
+        .and(warp::body::bytes())
+        .and(with(server.attachment_service.clone()))
+        .and_then(handler::upload_attachment);
+
+    let download_attachment = warp::path("attachments")
+        .and(warp::path::param::<uuid::Uuid>())
         .and(warp::path::end())
+        .and(warp::get())
         .and(with_verification(server.auth_service.clone()))
+        .and(with(server.attachment_service.clone()))
+        .and_then(handler::download_attachment);
+
+    let download_thumbnail = warp::path("attachments")
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path("thumbnails"))
+        .and(warp::path::param::<u32>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_verification(server.auth_service.clone()))
+        .and(with(server.attachment_service.clone()))
+        .and_then(handler::download_thumbnail);
+
+    let chat = warp::path("chat")
+        .and(warp::path::end())
+        .and(warp::get())
+        // Before the verification, so a request that isn't an upgrade doesn't burn a ticket.
         .and(warp::ws())
+        .and(with_chat_verification(server.auth_service.clone()))
         .and(with(server.chat_service.clone()))
         .map(
-            |user_id: UserId, ws: warp::ws::Ws, chat_service: Arc<dyn ChatService>| {
-                ws.on_upgrade(|socket| handler::join_chat(socket, user_id, chat_service))
+            |ws: warp::ws::Ws, user_id: UserId, protocol: Option<&'static str>, chat_service: Arc<dyn ChatService>| {
+                let reply = ws.on_upgrade(|socket| handler::join_chat(socket, user_id, chat_service));
+                match protocol {
+                    Some(protocol) => warp::reply::with_header(reply, "sec-websocket-protocol", protocol).into_response(),
+                    None => reply.into_response(),
+                }
             },
         );
 
-    captcha.or(login).or(signup).or(chat)
+    captcha
+        .or(login)
+        .or(signup)
+        .or(refresh)
+        .or(logout)
+        .or(logout_all)
+        .or(ws_ticket)
+        .or(upload_attachment)
+        .or(download_attachment)
+        .or(download_thumbnail)
+        .or(chat)
+        .recover(handle_rejection)
 }
 
 fn with<ServiceType>(
@@ -57,22 +139,70 @@ where
     warp::any().map(move || service.clone())
 }
 
+fn with_bearer_token() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::<String>(http::header::AUTHORIZATION.as_ref()).and_then(|header: String| async move {
+        match header.strip_prefix("Bearer ") {
+            Some(token) => Ok(token.to_string()),
+            None => Err(reject::custom(ApiError::InvalidToken)),
+        }
+    })
+}
+
+/// Extracts the caller's `UserId` from the bearer token.
+/// Revoked tokens are rejected by `AuthService::verify_token`.
 fn with_verification(
     auth_service: Arc<dyn AuthService>,
 ) -> impl Filter<Extract = (UserId,), Error = warp::Rejection> + Clone {
-    warp::header::<String>(http::header::AUTHORIZATION.as_ref()).and_then(move |token: String| {
+    with_bearer_token().and_then(move |token: String| {
         let auth_service = auth_service.clone();
         async move {
-            if let Some(token) = token.strip_prefix("Bearer ") {
-                let user_id = auth_service
-                    .verify_token(token)
-                    .await
+            auth_service
+                .verify_token(&token)
+                .await
+                .map_err(map_auth_error_to_api_error)
+                .map_err(reject::custom)
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatQuery {
+    ticket: Option<String>,
+}
+
+/// Like `with_verification`, but also accepts what browsers can send on a WebSocket handshake:
+/// a ws ticket in the `ticket` query parameter, or the access token after `bearer` in
+/// `Sec-WebSocket-Protocol`. Also extracts the subprotocol that has to be echoed back.
+fn with_chat_verification(
+    auth_service: Arc<dyn AuthService>,
+) -> impl Filter<Extract = (UserId, Option<&'static str>), Error = warp::Rejection> + Clone {
+    warp::query::<ChatQuery>()
+        .and(warp::header::optional::<String>(http::header::SEC_WEBSOCKET_PROTOCOL.as_ref()))
+        .and(warp::header::optional::<String>(http::header::AUTHORIZATION.as_ref()))
+        .and_then(move |query: ChatQuery, protocols: Option<String>, authorization: Option<String>| {
+            let auth_service = auth_service.clone();
+            async move {
+                let (result, protocol) = if let Some(ticket) = query.ticket {
+                    (auth_service.redeem_ws_ticket(&ticket).await, None)
+                } else if let Some(token) = protocols.as_deref().and_then(bearer_from_protocols) {
+                    (auth_service.verify_token(token).await, Some(BEARER_PROTOCOL))
+                } else if let Some(token) = authorization.as_deref().and_then(|header| header.strip_prefix("Bearer ")) {
+                    (auth_service.verify_token(token).await, None)
+                } else {
+                    return Err(reject::custom(ApiError::InvalidToken));
+                };
+                let user_id = result
                     .map_err(map_auth_error_to_api_error)
                     .map_err(reject::custom)?;
-                Ok(user_id)
-            } else {
-                Err(reject::custom(ApiError::InvalidToken))
+                Ok::<_, warp::Rejection>((user_id, protocol))
             }
-        }
-    })
+        })
+        .untuple_one()
 }
+
+/// Finds the token in a `Sec-WebSocket-Protocol` value such as `bearer, <token>`.
+fn bearer_from_protocols(protocols: &str) -> Option<&str> {
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == BEARER_PROTOCOL)?;
+    protocols.next()
+}
\ No newline at end of file
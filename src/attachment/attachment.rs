@@ -0,0 +1,146 @@
+use std::fmt::Debug;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::domain::{AttachmentId, ConversationId, UserId};
+
+#[derive(Debug, Error)]
+pub enum AttachmentError {
+    #[error("Attachment is empty")]
+    Empty,
+    #[error("Attachments may be at most {0} bytes")]
+    TooLarge(u64),
+    #[error("Upload quota of {0} bytes exceeded")]
+    QuotaExceeded(u64),
+    #[error("Image could not be read")]
+    MalformedImage,
+    #[error("Attachment not found")]
+    NotFound,
+    #[error("Attachment was already posted")]
+    AlreadyPosted,
+    #[error("Not a member of the conversation")]
+    NotAMember,
+    #[error("Internal error: {0}")]
+    InternalError(#[from] anyhow::Error),
+}
+
+#[derive(Debug)]
+pub struct AttachmentConfig {
+    pub max_size: u64,  // bytes
+    pub user_quota: u64,  // bytes
+    /// Longest edge of each thumbnail rendered for uploaded images.
+    pub thumbnail_sizes: Vec<u32>,  // pixels
+}
+
+/// The server's record of an uploaded file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: AttachmentId,
+    pub owner: UserId,
+    /// Set by the first message referencing the attachment. Until then only the owner may download it.
+    pub conversation_id: Option<ConversationId>,
+    pub name: Option<String>,
+    /// Sniffed from the content, whatever the uploader claimed.
+    pub content_type: String,
+    pub size: u64,
+    pub uploaded_at: DateTime<Utc>,
+    /// Filled in by the background job once they are rendered.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    /// The longest edge it was rendered for, which addresses it.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+}
+
+/// What the members of a conversation get to see of an attachment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub id: AttachmentId,
+    pub name: Option<String>,
+    pub content_type: String,
+    pub size: u64,
+    /// Smallest first. Empty for anything but images, for images too small to need any,
+    /// and until they are rendered.
+    #[serde(default)]
+    pub thumbnails: Vec<ThumbnailInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailInfo {
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+    pub url: String,
+}
+
+impl From<&Attachment> for AttachmentInfo {
+    fn from(attachment: &Attachment) -> Self {
+        let thumbnails = attachment
+            .thumbnails
+            .iter()
+            .map(|thumbnail| ThumbnailInfo {
+                width: thumbnail.width,
+                height: thumbnail.height,
+                content_type: thumbnail.content_type.clone(),
+                url: format!("/api/v1/attachments/{}/thumbnails/{}", attachment.id.0, thumbnail.size),
+            })
+            .collect();
+        Self {
+            id: attachment.id,
+            name: attachment.name.clone(),
+            content_type: attachment.content_type.clone(),
+            size: attachment.size,
+            thumbnails,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait AttachmentService: Debug + Send + Sync {
+    /// Uploads larger than this are rejected before their body is read.
+    fn max_size(&self) -> u64;
+
+    /// Store an upload and count it against the owner's quota.
+    /// Images are stored without their metadata, and get thumbnails rendered in the background.
+    async fn upload(&self, owner: &UserId, name: Option<String>, bytes: &[u8]) -> Result<Attachment, AttachmentError>;
+
+    /// Post the owner's attachments to a conversation, whose members may download them from then on.
+    /// Doesn't wait for thumbnails still being rendered, see `wait_for_thumbnails`.
+    /// Returns `AttachmentError::NotFound` for attachments of other users, and
+    /// `AttachmentError::AlreadyPosted` for attachments posted before, to any conversation.
+    /// On error, none of the attachments is posted.
+    async fn post(
+        &self,
+        owner: &UserId,
+        conversation_id: &ConversationId,
+        ids: &[AttachmentId],
+    ) -> Result<Vec<Attachment>, AttachmentError>;
+
+    /// Take posted attachments back to their owners, when the message they were posted in couldn't be stored.
+    async fn unpost(&self, ids: &[AttachmentId]);
+
+    /// Wait until none of the attachments has thumbnails still being rendered,
+    /// and return them as they are then.
+    async fn wait_for_thumbnails(&self, ids: &[AttachmentId]) -> Result<Vec<Attachment>, AttachmentError>;
+
+    /// Delete attachments and their thumbnails, e.g. with the message they were posted in,
+    /// and give their size back to the owners' quotas. Unknown ids are skipped.
+    async fn delete(&self, ids: &[AttachmentId]) -> Result<(), AttachmentError>;
+
+    /// The attachment and its content, if the user is its owner or a member of its conversation.
+    async fn download(&self, user_id: &UserId, id: AttachmentId) -> Result<(Attachment, Vec<u8>), AttachmentError>;
+
+    /// One thumbnail of the attachment, under the same rules as `download`.
+    async fn download_thumbnail(
+        &self,
+        user_id: &UserId,
+        id: AttachmentId,
+        size: u32,
+    ) -> Result<(Thumbnail, Vec<u8>), AttachmentError>;
+}
@@ -0,0 +1,434 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::anyhow;
+use chrono::Utc;
+use dashmap::DashMap;
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinHandle;
+use crate::attachment::*;
+use crate::domain::{AttachmentId, ConversationId, UserId};
+use crate::logger::*;
+use crate::user::UserService;
+
+/// Longer names are cut, they are only shown to users.
+const MAX_NAME_LENGTH: usize = 255;  // characters
+/// Images waiting for their thumbnails. Uploads wait while the queue is full.
+const THUMBNAIL_QUEUE_CAPACITY: usize = 64;
+
+impl Debug for FileAttachmentService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileAttachmentService")
+            .field("config", &self.state.config)
+            .field("root", &self.state.root)
+            .field("attachments", &self.state.attachments.len())
+            .field("pending_thumbnails", &self.state.pending_thumbnails.len())
+            .finish()
+    }
+}
+
+/// Keeps each upload in `root` as `<id>`, next to its metadata in `<id>.json`
+/// and its thumbnails in `<id>-<size>`. The metadata of every attachment is also kept
+/// in memory and read back on startup.
+pub struct FileAttachmentService {
+    state: Arc<AttachmentState>,
+    thumbnail_jobs: Sender<ThumbnailJob>,
+    thumbnail_handle: JoinHandle<()>,
+}
+
+/// State shared by the service and the thumbnail worker.
+struct AttachmentState {
+    config: AttachmentConfig,
+    root: PathBuf,
+    attachments: DashMap<AttachmentId, Attachment>,
+    /// Bytes uploaded by each user.
+    usage: DashMap<UserId, u64>,
+    /// Images whose thumbnails are still being rendered. The value turns `true` when they are done.
+    pending_thumbnails: DashMap<AttachmentId, watch::Receiver<bool>>,
+    /// Held while writing metadata, so an older version never replaces a newer one.
+    metadata_lock: Mutex<()>,
+    user_service: Arc<dyn UserService>,
+}
+
+struct ThumbnailJob {
+    id: AttachmentId,
+    bytes: Vec<u8>,
+    orientation: Option<u16>,
+    done: watch::Sender<bool>,
+}
+
+impl FileAttachmentService {
+    pub fn open(
+        config: AttachmentConfig,
+        root: impl Into<PathBuf>,
+        user_service: Arc<dyn UserService>,
+    ) -> anyhow::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        let attachments = DashMap::new();
+        let usage: DashMap<UserId, u64> = DashMap::new();
+        for entry in std::fs::read_dir(&root)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let bytes = std::fs::read(&path)?;
+            let attachment = serde_json::from_slice::<Attachment>(&bytes)
+                .map_err(|e| anyhow!("Corrupt attachment metadata {:?}: {}", path, e))?;
+            *usage.entry(attachment.owner.clone()).or_default() += attachment.size;
+            attachments.insert(attachment.id, attachment);
+        }
+
+        let state = Arc::new(AttachmentState {
+            config,
+            root,
+            attachments,
+            usage,
+            pending_thumbnails: DashMap::new(),
+            metadata_lock: Mutex::new(()),
+            user_service,
+        });
+        let (thumbnail_jobs, from_uploads) = channel(THUMBNAIL_QUEUE_CAPACITY);
+        let thumbnail_handle = tokio::spawn(thumbnail_worker(from_uploads, state.clone()));
+
+        Ok(Self {
+            state,
+            thumbnail_jobs,
+            thumbnail_handle,
+        })
+    }
+
+    async fn store(&self, attachment: Attachment, bytes: &[u8]) -> anyhow::Result<()> {
+        let id = attachment.id;
+        let blob_path = self.state.blob_path(id);
+        tokio::fs::write(&blob_path, bytes).await?;
+        self.state.attachments.insert(id, attachment);
+        if let Err(e) = self.state.persist(id).await {
+            self.state.attachments.remove(&id);
+            let _ = tokio::fs::remove_file(&blob_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn release_quota(&self, owner: &UserId, size: u64) {
+        if let Some(mut used) = self.state.usage.get_mut(owner) {
+            *used = used.saturating_sub(size);
+        }
+    }
+
+    /// Bind an attachment of the owner to the conversation, unless it was posted before.
+    fn bind(&self, owner: &UserId, conversation_id: &ConversationId, id: AttachmentId) -> Result<Attachment, AttachmentError> {
+        let mut attachment = self
+            .state
+            .attachments
+            .get_mut(&id)
+            .filter(|attachment| attachment.owner == *owner)
+            .ok_or(AttachmentError::NotFound)?;
+        // Even to the same conversation: one attachment backs one message, or deleting either would break the other.
+        if attachment.conversation_id.is_some() {
+            return Err(AttachmentError::AlreadyPosted);
+        }
+        attachment.conversation_id = Some(conversation_id.clone());
+        Ok(attachment.clone())
+    }
+
+    /// The attachment, if the user is its owner or a member of the conversation it was posted to.
+    async fn authorize(&self, user_id: &UserId, id: AttachmentId) -> Result<Attachment, AttachmentError> {
+        let attachment = self
+            .state
+            .attachments
+            .get(&id)
+            .map(|attachment| attachment.clone())
+            .ok_or(AttachmentError::NotFound)?;
+        let allowed = match &attachment.conversation_id {
+            Some(conversation_id) => self.state.user_service.is_member(user_id, conversation_id).await?,
+            // Nobody else knows about an attachment that was never posted.
+            None if attachment.owner != *user_id => return Err(AttachmentError::NotFound),
+            None => true,
+        };
+        if !allowed {
+            return Err(AttachmentError::NotAMember);
+        }
+        Ok(attachment)
+    }
+}
+
+impl Drop for FileAttachmentService {
+    fn drop(&mut self) {
+        self.thumbnail_handle.abort();
+    }
+}
+
+impl AttachmentState {
+    fn blob_path(&self, id: AttachmentId) -> PathBuf {
+        self.root.join(id.0.to_string())
+    }
+
+    fn metadata_path(&self, id: AttachmentId) -> PathBuf {
+        self.root.join(format!("{}.json", id.0))
+    }
+
+    fn thumbnail_path(&self, id: AttachmentId, size: u32) -> PathBuf {
+        self.root.join(format!("{}-{}", id.0, size))
+    }
+
+    /// Write the current metadata of the attachment.
+    async fn persist(&self, id: AttachmentId) -> anyhow::Result<()> {
+        let _guard = self.metadata_lock.lock().await;
+        let attachment = self
+            .attachments
+            .get(&id)
+            .map(|attachment| attachment.clone())
+            .ok_or(anyhow!("Attachment not found: {:?}", id))?;
+        let path = self.metadata_path(id);
+        // Write to a temporary file first so a crash never leaves truncated metadata behind.
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(&attachment)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+}
+
+/// Drop what could break out of a header or a path, and cut the name to a sane length.
+fn clean_name(name: String) -> Option<String> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\\' | '/'))
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Renders the thumbnails of one image at a time, off the async workers.
+async fn thumbnail_worker(mut jobs: Receiver<ThumbnailJob>, state: Arc<AttachmentState>) {
+    while let Some(job) = jobs.recv().await {
+        let id = job.id;
+        // Images that fail to decode just go without thumbnails.
+        if let Err(e) = add_thumbnails(&state, id, job.bytes, job.orientation).await {
+            debug!("No thumbnails for attachment {:?}: {}", id, e);
+        }
+        state.pending_thumbnails.remove(&id);
+        let _ = job.done.send(true);
+    }
+}
+
+async fn add_thumbnails(
+    state: &AttachmentState,
+    id: AttachmentId,
+    bytes: Vec<u8>,
+    orientation: Option<u16>,
+) -> anyhow::Result<()> {
+    let sizes = state.config.thumbnail_sizes.clone();
+    let rendered = tokio::task::spawn_blocking(move || render_thumbnails(&bytes, &sizes, orientation)).await??;
+    if rendered.is_empty() {
+        return Ok(());
+    }
+
+    let mut thumbnails = Vec::with_capacity(rendered.len());
+    for thumbnail in rendered {
+        tokio::fs::write(state.thumbnail_path(id, thumbnail.size), &thumbnail.bytes).await?;
+        thumbnails.push(Thumbnail {
+            size: thumbnail.size,
+            width: thumbnail.width,
+            height: thumbnail.height,
+            content_type: thumbnail.content_type.to_string(),
+        });
+    }
+    thumbnails.sort_by_key(|thumbnail| thumbnail.size);
+    let Some(mut attachment) = state.attachments.get_mut(&id) else {
+        // Deleted while rendering.
+        for thumbnail in thumbnails {
+            let _ = tokio::fs::remove_file(state.thumbnail_path(id, thumbnail.size)).await;
+        }
+        return Ok(());
+    };
+    attachment.thumbnails = thumbnails;
+    drop(attachment);
+    state.persist(id).await
+}
+
+#[async_trait::async_trait]
+impl AttachmentService for FileAttachmentService {
+    fn max_size(&self) -> u64 {
+        self.state.config.max_size
+    }
+
+    async fn upload(&self, owner: &UserId, name: Option<String>, bytes: &[u8]) -> Result<Attachment, AttachmentError> {
+        if bytes.is_empty() {
+            return Err(AttachmentError::Empty);
+        }
+        if bytes.len() as u64 > self.state.config.max_size {
+            return Err(AttachmentError::TooLarge(self.state.config.max_size));
+        }
+        let content_type = sniff_content_type(bytes);
+        // An image that can't be parsed might still carry a location, so it isn't stored at all.
+        let stripped = strip_metadata(content_type, bytes).map_err(|_| AttachmentError::MalformedImage)?;
+        let (bytes, orientation) = match &stripped {
+            Some((stripped, orientation)) => (stripped.as_slice(), *orientation),
+            None => (bytes, None),
+        };
+
+        let size = bytes.len() as u64;
+        // Reserved up front, so concurrent uploads can't overshoot the quota together.
+        {
+            let mut used = self.state.usage.entry(owner.clone()).or_default();
+            if *used + size > self.state.config.user_quota {
+                return Err(AttachmentError::QuotaExceeded(self.state.config.user_quota));
+            }
+            *used += size;
+        }
+
+        let attachment = Attachment {
+            id: AttachmentId(uuid::Uuid::new_v4()),
+            owner: owner.clone(),
+            conversation_id: None,
+            name: name.and_then(clean_name),
+            content_type: content_type.to_string(),
+            size,
+            uploaded_at: Utc::now(),
+            thumbnails: vec![],
+        };
+        if let Err(e) = self.store(attachment.clone(), bytes).await {
+            self.release_quota(owner, size);
+            return Err(AttachmentError::InternalError(e));
+        }
+
+        if content_type.starts_with("image/") && !self.state.config.thumbnail_sizes.is_empty() {
+            let (done, pending) = watch::channel(false);
+            self.state.pending_thumbnails.insert(attachment.id, pending);
+            let job = ThumbnailJob {
+                id: attachment.id,
+                bytes: bytes.to_vec(),
+                orientation,
+                done,
+            };
+            if self.thumbnail_jobs.send(job).await.is_err() {
+                self.state.pending_thumbnails.remove(&attachment.id);
+                warn!("Thumbnail worker is gone, attachment {:?} has no thumbnails", attachment.id);
+            }
+        }
+        Ok(attachment)
+    }
+
+    async fn post(
+        &self,
+        owner: &UserId,
+        conversation_id: &ConversationId,
+        ids: &[AttachmentId],
+    ) -> Result<Vec<Attachment>, AttachmentError> {
+        let mut posted = Vec::with_capacity(ids.len());
+        for (bound, id) in ids.iter().enumerate() {
+            let attachment = match self.bind(owner, conversation_id, *id) {
+                Ok(attachment) => attachment,
+                Err(e) => {
+                    self.unpost(&ids[..bound]).await;
+                    return Err(e);
+                }
+            };
+            if let Err(e) = self.state.persist(*id).await {
+                self.unpost(&ids[..=bound]).await;
+                return Err(AttachmentError::InternalError(e));
+            }
+            posted.push(attachment);
+        }
+        Ok(posted)
+    }
+
+    async fn unpost(&self, ids: &[AttachmentId]) {
+        for id in ids {
+            let Some(mut attachment) = self.state.attachments.get_mut(id) else {
+                continue;
+            };
+            attachment.conversation_id = None;
+            drop(attachment);
+            if let Err(e) = self.state.persist(*id).await {
+                warn!("Failed to unpost attachment {:?}: {}", id, e);
+            }
+        }
+    }
+
+    async fn wait_for_thumbnails(&self, ids: &[AttachmentId]) -> Result<Vec<Attachment>, AttachmentError> {
+        let mut attachments = Vec::with_capacity(ids.len());
+        for id in ids {
+            let done = self.state.pending_thumbnails.get(id).map(|done| done.clone());
+            if let Some(mut done) = done {
+                // Returns early if the worker is gone.
+                let _ = done.wait_for(|done| *done).await;
+            }
+            let attachment = self
+                .state
+                .attachments
+                .get(id)
+                .map(|attachment| attachment.clone())
+                .ok_or(AttachmentError::NotFound)?;
+            attachments.push(attachment);
+        }
+        Ok(attachments)
+    }
+
+    async fn delete(&self, ids: &[AttachmentId]) -> Result<(), AttachmentError> {
+        // Held throughout, so a concurrent `persist` can't write the metadata of a deleted attachment back.
+        let _guard = self.state.metadata_lock.lock().await;
+        let mut failed = None;
+        for id in ids {
+            // Gone from memory first, so downloads stop even if a file can't be removed.
+            let Some((_, attachment)) = self.state.attachments.remove(id) else {
+                continue;
+            };
+            self.release_quota(&attachment.owner, attachment.size);
+            // Metadata goes first, so a half-deleted attachment doesn't come back on startup.
+            let thumbnail_sizes = attachment
+                .thumbnails
+                .iter()
+                .map(|thumbnail| thumbnail.size)
+                .chain(self.state.config.thumbnail_sizes.iter().copied());
+            let paths = [self.state.metadata_path(*id), self.state.blob_path(*id)]
+                .into_iter()
+                .chain(thumbnail_sizes.map(|size| self.state.thumbnail_path(*id, size)));
+            for path in paths {
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        failed = Some(anyhow!("Failed to remove {:?}: {}", path, e));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(AttachmentError::InternalError(e)),
+            None => Ok(()),
+        }
+    }
+
+    async fn download(&self, user_id: &UserId, id: AttachmentId) -> Result<(Attachment, Vec<u8>), AttachmentError> {
+        let attachment = self.authorize(user_id, id).await?;
+        let path = self.state.blob_path(id);
+        let bytes = tokio::fs::read(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read attachment {:?}: {}", path, e))?;
+        Ok((attachment, bytes))
+    }
+
+    async fn download_thumbnail(
+        &self,
+        user_id: &UserId,
+        id: AttachmentId,
+        size: u32,
+    ) -> Result<(Thumbnail, Vec<u8>), AttachmentError> {
+        let attachment = self.authorize(user_id, id).await?;
+        let thumbnail = attachment
+            .thumbnails
+            .into_iter()
+            .find(|thumbnail| thumbnail.size == size)
+            .ok_or(AttachmentError::NotFound)?;
+        let path = self.state.thumbnail_path(id, size);
+        let bytes = tokio::fs::read(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read thumbnail {:?}: {}", path, e))?;
+        Ok((thumbnail, bytes))
+    }
+}
@@ -0,0 +1,387 @@
+/// EXIF orientation, the only tag kept: without it, photos taken sideways display sideways.
+const TAG_ORIENTATION: u16 = 0x0112;
+
+/// PNG chunks that may carry camera details or locations.
+const PNG_METADATA_CHUNKS: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"iTXt", b"zTXt"];
+
+/// Flags in the WebP `VP8X` header announcing EXIF and XMP chunks.
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+
+/// The stripped image and its EXIF orientation.
+pub type StrippedImage = (Vec<u8>, Option<u16>);
+
+/// An image `strip_metadata` couldn't parse, so it can't tell what metadata it carries.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MalformedImage;
+
+/// The image without its metadata segments, and its EXIF orientation if any.
+/// Works on the encoded bytes, so the pixels are left untouched. Returns `Ok(None)` for types
+/// it doesn't handle, and `MalformedImage` for files it can't parse, which must not be stored.
+pub fn strip_metadata(content_type: &str, bytes: &[u8]) -> Result<Option<StrippedImage>, MalformedImage> {
+    let stripped = match content_type {
+        "image/jpeg" => strip_jpeg(bytes),
+        "image/png" => strip_png(bytes).map(|stripped| (stripped, None)),
+        "image/webp" => strip_webp(bytes),
+        _ => return Ok(None),
+    };
+    stripped.map(Some).ok_or(MalformedImage)
+}
+
+/// Drops APP1 (EXIF, XMP), APP13 (IPTC) and comment segments. A rotated photo gets a minimal
+/// EXIF segment back with nothing but its orientation.
+fn strip_jpeg(bytes: &[u8]) -> Option<StrippedImage> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut segments = Vec::with_capacity(bytes.len());
+    let mut orientation = None;
+    let mut position = 2;
+    loop {
+        if *bytes.get(position)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(position + 1)?;
+        match marker {
+            // Fill byte before a marker.
+            0xff => position += 1,
+            // Start of scan or end of image: the rest is image data.
+            0xda | 0xd9 => {
+                segments.extend_from_slice(&bytes[position..]);
+                break;
+            }
+            // Markers without a length.
+            0x01 | 0xd0..=0xd7 => {
+                segments.extend_from_slice(&bytes[position..position + 2]);
+                position += 2;
+            }
+            _ => {
+                let length = u16::from_be_bytes([*bytes.get(position + 2)?, *bytes.get(position + 3)?]) as usize;
+                // The length counts its own two bytes.
+                if length < 2 {
+                    return None;
+                }
+                let end = position + 2 + length;
+                let segment = bytes.get(position..end)?;
+                match marker {
+                    0xe1 => {
+                        if let Some(tiff) = segment.get(4..).and_then(|payload| payload.strip_prefix(b"Exif\0\0")) {
+                            orientation = orientation.or(exif_orientation(tiff));
+                        }
+                    }
+                    0xed | 0xfe => {}
+                    _ => segments.extend_from_slice(segment),
+                }
+                position = end;
+            }
+        }
+    }
+
+    let mut stripped = Vec::with_capacity(segments.len() + 36);
+    stripped.extend_from_slice(&[0xff, 0xd8]);
+    if let Some(orientation) = orientation.filter(|orientation| *orientation != 1) {
+        stripped.extend_from_slice(&orientation_segment(orientation));
+    }
+    stripped.extend_from_slice(&segments);
+    Some((stripped, orientation))
+}
+
+/// Finds the orientation in the first IFD of EXIF data, which is laid out like a TIFF file.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let count = read_u16(ifd)? as usize;
+    (0..count)
+        .map(|index| ifd + 2 + index * 12)
+        .find(|entry| read_u16(*entry) == Some(TAG_ORIENTATION))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// An APP1 segment with EXIF data holding only the orientation.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = vec![0xff, 0xe1, 0x00, 0x22];
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&orientation_exif(orientation));
+    segment
+}
+
+/// Big endian EXIF data with a single IFD holding only the orientation.
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    // TIFF header, with the IFD right after it.
+    let mut exif = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+    exif.extend_from_slice(&1u16.to_be_bytes());
+    // One SHORT value, padded to four bytes.
+    exif.extend_from_slice(&TAG_ORIENTATION.to_be_bytes());
+    exif.extend_from_slice(&3u16.to_be_bytes());
+    exif.extend_from_slice(&1u32.to_be_bytes());
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    // No next IFD.
+    exif.extend_from_slice(&0u32.to_be_bytes());
+    exif
+}
+
+/// Chunks carry their own checksums, so leaving some out keeps the rest valid.
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !bytes.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut stripped = Vec::with_capacity(bytes.len());
+    stripped.extend_from_slice(SIGNATURE);
+    let mut position = SIGNATURE.len();
+    while position < bytes.len() {
+        let length = u32::from_be_bytes(bytes.get(position..position + 4)?.try_into().ok()?) as usize;
+        // Length, type, data and checksum.
+        let end = position.checked_add(12)?.checked_add(length)?;
+        let chunk = bytes.get(position..end)?;
+        if !PNG_METADATA_CHUNKS.iter().any(|name| &chunk[4..8] == *name) {
+            stripped.extend_from_slice(chunk);
+        }
+        position = end;
+    }
+    Some(stripped)
+}
+
+/// Drops EXIF and XMP chunks and clears their flags in the `VP8X` header. A rotated image
+/// gets an EXIF chunk back with nothing but its orientation.
+fn strip_webp(bytes: &[u8]) -> Option<StrippedImage> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let riff_length = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+    let body = bytes.get(12..riff_length.checked_add(8)?)?;
+    let mut chunks = Vec::with_capacity(body.len());
+    let mut orientation = None;
+    // Where the flags of the `VP8X` header ended up in `chunks`.
+    let mut vp8x_flags = None;
+    let mut position = 0;
+    while position < body.len() {
+        let header = body.get(position..position + 8)?;
+        let length = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        // Type and length, then the data padded to an even length.
+        let end = position.checked_add(8)?.checked_add(length)?.checked_add(length % 2)?;
+        let chunk = body.get(position..end)?;
+        match &header[..4] {
+            b"EXIF" => {
+                // Some writers keep the JPEG style prefix.
+                let exif = &chunk[8..8 + length];
+                orientation = orientation.or(exif_orientation(exif.strip_prefix(b"Exif\0\0").unwrap_or(exif)));
+            }
+            b"XMP " => {}
+            name => {
+                if name == b"VP8X" {
+                    if length < 10 {
+                        return None;
+                    }
+                    vp8x_flags = Some(chunks.len() + 8);
+                }
+                chunks.extend_from_slice(chunk);
+            }
+        }
+        position = end;
+    }
+
+    // Only the extended format may carry EXIF, the simple one has nowhere to announce it.
+    let orientation_chunk = orientation
+        .filter(|orientation| *orientation != 1 && vp8x_flags.is_some())
+        .map(|orientation| {
+            let exif = orientation_exif(orientation);
+            let mut chunk = b"EXIF".to_vec();
+            chunk.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(&exif);
+            chunk
+        });
+    if let Some(flags) = vp8x_flags {
+        chunks[flags] &= !(VP8X_EXIF | VP8X_XMP);
+        if orientation_chunk.is_some() {
+            chunks[flags] |= VP8X_EXIF;
+        }
+    }
+    // Metadata chunks go last, after the image data.
+    if let Some(orientation_chunk) = orientation_chunk {
+        chunks.extend_from_slice(&orientation_chunk);
+    }
+
+    let mut stripped = Vec::with_capacity(chunks.len() + 12);
+    stripped.extend_from_slice(b"RIFF");
+    stripped.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    stripped.extend_from_slice(b"WEBP");
+    stripped.extend_from_slice(&chunks);
+    Some((stripped, orientation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian EXIF data with a GPS pointer and the orientation.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut exif = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        exif.extend_from_slice(&2u16.to_le_bytes());
+        for (tag, kind, value) in [(0x8825u16, 4u16, 0u32), (TAG_ORIENTATION, 3, orientation as u32)] {
+            exif.extend_from_slice(&tag.to_le_bytes());
+            exif.extend_from_slice(&kind.to_le_bytes());
+            exif.extend_from_slice(&1u32.to_le_bytes());
+            exif.extend_from_slice(&value.to_le_bytes());
+        }
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn exif_segment(orientation: u16) -> Vec<u8> {
+        jpeg_segment(0xe1, &[b"Exif\0\0".as_slice(), &exif(orientation)].concat())
+    }
+
+    /// A tiny real JPEG, with `segments` right after its start of image marker.
+    fn jpeg_with(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        let image = image::RgbImage::from_pixel(4, 2, image::Rgb([200, 100, 50]));
+        image::codecs::jpeg::JpegEncoder::new(&mut encoded).encode_image(&image).unwrap();
+        [&encoded[..2], &segments.concat(), &encoded[2..]].concat()
+    }
+
+    fn webp_chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        [b"RIFF".as_slice(), &(chunks.len() as u32 + 4).to_le_bytes(), b"WEBP", &chunks].concat()
+    }
+
+    fn vp8x(flags: u8) -> Vec<u8> {
+        webp_chunk(b"VP8X", &[flags, 0, 0, 0, 3, 0, 0, 1, 0, 0])
+    }
+
+    #[test]
+    fn jpeg_keeps_only_the_orientation() {
+        let comment = jpeg_segment(0xfe, b"taken at home");
+        let iptc = jpeg_segment(0xed, b"Photoshop 3.0\0");
+        let bytes = jpeg_with(&[exif_segment(6), comment, iptc]);
+
+        let (stripped, orientation) = strip_metadata("image/jpeg", &bytes).unwrap().unwrap();
+        assert_eq!(orientation, Some(6));
+        assert_eq!(stripped, jpeg_with(&[orientation_segment(6)]));
+        assert_eq!(exif_orientation(&orientation_segment(6)[10..]), Some(6));
+        let decoded = image::load_from_memory(&stripped).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 2));
+    }
+
+    #[test]
+    fn jpeg_upright_gets_no_exif_back() {
+        let (stripped, orientation) = strip_metadata("image/jpeg", &jpeg_with(&[exif_segment(1)])).unwrap().unwrap();
+        assert_eq!(orientation, Some(1));
+        assert_eq!(stripped, jpeg_with(&[]));
+    }
+
+    #[test]
+    fn jpeg_with_truncated_segment_is_rejected() {
+        let mut bytes = vec![0xff, 0xd8];
+        bytes.extend_from_slice(&[0xff, 0xe1, 0x00, 0x40]);
+        bytes.extend_from_slice(b"Exif\0\0");
+        assert_eq!(strip_metadata("image/jpeg", &bytes), Err(MalformedImage));
+        // Cut off inside the length itself.
+        assert_eq!(strip_metadata("image/jpeg", &[0xff, 0xd8, 0xff, 0xe0, 0x00]), Err(MalformedImage));
+    }
+
+    #[test]
+    fn jpeg_with_zero_length_segment_is_rejected() {
+        let bytes = [&[0xff, 0xd8, 0xff, 0xe1, 0x00, 0x00][..], &jpeg_with(&[])[2..]].concat();
+        assert_eq!(strip_metadata("image/jpeg", &bytes), Err(MalformedImage));
+    }
+
+    #[test]
+    fn other_types_are_not_touched() {
+        assert_eq!(strip_metadata("image/gif", b"GIF89a"), Ok(None));
+    }
+
+    #[test]
+    fn exif_with_truncated_ifd_has_no_orientation() {
+        let exif = exif(6);
+        assert_eq!(exif_orientation(&exif), Some(6));
+        assert_eq!(exif_orientation(&exif[..20]), None);
+        assert_eq!(exif_orientation(b"II\x2a\x00\xff\xff\xff\xff"), None);
+    }
+
+    #[test]
+    fn png_drops_text_chunks() {
+        let mut encoded = Vec::new();
+        let image = image::RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 0, 128]));
+        image::DynamicImage::ImageRgba8(image)
+            .write_to(&mut std::io::Cursor::new(&mut encoded), image::ImageOutputFormat::Png)
+            .unwrap();
+        // Right after the signature and IHDR.
+        let text = [&4u32.to_be_bytes()[..], b"tEXt", b"a\0bc", &[0, 0, 0, 0]].concat();
+        let bytes = [&encoded[..33], &text, &encoded[33..]].concat();
+
+        let (stripped, orientation) = strip_metadata("image/png", &bytes).unwrap().unwrap();
+        assert_eq!((stripped, orientation), (encoded, None));
+        assert_eq!(strip_metadata("image/png", &bytes[..40]), Err(MalformedImage));
+    }
+
+    #[test]
+    fn webp_keeps_only_the_orientation() {
+        let image = webp_chunk(b"VP8L", b"pixels");
+        let bytes = webp(&[
+            vp8x(VP8X_EXIF | VP8X_XMP | 0x10),
+            image.clone(),
+            webp_chunk(b"EXIF", &exif(8)),
+            webp_chunk(b"XMP ", b"<x:xmpmeta/>"),
+        ]);
+
+        let (stripped, orientation) = strip_metadata("image/webp", &bytes).unwrap().unwrap();
+        assert_eq!(orientation, Some(8));
+        let expected = webp(&[vp8x(VP8X_EXIF | 0x10), image, webp_chunk(b"EXIF", &orientation_exif(8))]);
+        assert_eq!(stripped, expected);
+    }
+
+    #[test]
+    fn webp_upright_clears_the_metadata_flags() {
+        let image = webp_chunk(b"VP8L", b"pixel");
+        // With the JPEG style prefix some writers add.
+        let prefixed = [b"Exif\0\0".as_slice(), &exif(1)].concat();
+        let bytes = webp(&[vp8x(VP8X_EXIF | VP8X_XMP), image.clone(), webp_chunk(b"EXIF", &prefixed)]);
+
+        let (stripped, orientation) = strip_metadata("image/webp", &bytes).unwrap().unwrap();
+        assert_eq!(orientation, Some(1));
+        assert_eq!(stripped, webp(&[vp8x(0), image]));
+    }
+
+    #[test]
+    fn webp_with_truncated_chunk_is_rejected() {
+        let mut bytes = webp(&[vp8x(0), webp_chunk(b"VP8L", b"pixels")]);
+        // Claims more data than there is.
+        bytes[34..38].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(strip_metadata("image/webp", &bytes), Err(MalformedImage));
+        let bytes = webp(&[vp8x(0)]);
+        assert_eq!(strip_metadata("image/webp", &bytes[..bytes.len() - 1]), Err(MalformedImage));
+    }
+}
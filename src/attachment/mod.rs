@@ -0,0 +1,11 @@
+mod attachment;
+mod file_attachment;
+mod metadata;
+mod sniff;
+mod thumbnail;
+
+pub use attachment::*;
+pub use file_attachment::*;
+pub use metadata::*;
+pub use sniff::*;
+pub use thumbnail::*;
\ No newline at end of file
@@ -0,0 +1,24 @@
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+];
+
+/// Guess the content type from the leading bytes, falling back to plain text for UTF-8 without NULs
+/// and to `application/octet-stream` for anything else. Markup is never recognized,
+/// so an upload can't be served as HTML.
+pub fn sniff_content_type(bytes: &[u8]) -> &'static str {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| bytes.starts_with(signature)) {
+        return content_type;
+    }
+    if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok() {
+        return "text/plain; charset=utf-8";
+    }
+    "application/octet-stream"
+}
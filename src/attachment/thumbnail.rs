@@ -0,0 +1,65 @@
+use std::io::Cursor;
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageOutputFormat};
+
+/// Larger images are not decoded at all, to keep a small upload from taking a lot of memory.
+const MAX_DIMENSION: u32 = 12_000;  // pixels
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug)]
+pub struct RenderedThumbnail {
+    /// The longest edge it was rendered for.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Render a thumbnail for each size smaller than the image, upright according to its EXIF orientation.
+/// Thumbnails are JPEGs, or PNGs for images with transparency, and carry no metadata.
+pub fn render_thumbnails(bytes: &[u8], sizes: &[u32], orientation: Option<u16>) -> Result<Vec<RenderedThumbnail>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = orient(reader.decode()?, orientation.unwrap_or(1));
+
+    let longest_edge = image.width().max(image.height());
+    let mut thumbnails = Vec::new();
+    for size in sizes.iter().copied().filter(|size| *size > 0 && *size < longest_edge) {
+        let thumbnail = image.thumbnail(size, size);
+        let mut encoded = Vec::new();
+        let content_type = if thumbnail.color().has_alpha() {
+            thumbnail.write_to(&mut Cursor::new(&mut encoded), ImageOutputFormat::Png)?;
+            "image/png"
+        } else {
+            JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).encode_image(&thumbnail.to_rgb8())?;
+            "image/jpeg"
+        };
+        thumbnails.push(RenderedThumbnail {
+            size,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            content_type,
+            bytes: encoded,
+        });
+    }
+    Ok(thumbnails)
+}
+
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt::Debug;
 use serde::{Serialize};
 use thiserror::Error;
 use crate::domain::UserId;
@@ -16,6 +17,8 @@ pub enum AuthError {
     InvalidRefreshToken,
     #[error("refresh token expired")]
     RefreshTokenExpired,
+    #[error("refresh token reuse detected")]
+    RefreshTokenReused,
     #[error("internal error: {0}")]
     InternalError(#[from] anyhow::Error),
 }
@@ -28,6 +31,12 @@ pub struct AuthTokens {
     pub refresh_expires_in: u64,  // seconds
 }
 
+#[derive(Debug, Serialize)]
+pub struct WsTicket {
+    pub ticket: String,
+    pub expires_in: u64,  // seconds
+}
+
 #[derive(Debug)]
 pub struct LoginInput {
     pub username: String,
@@ -47,9 +56,26 @@ pub struct SignupInput {
 }
 
 #[async_trait::async_trait]
-pub trait AuthService: Send + Sync {
+pub trait AuthService: Debug + Send + Sync {
     async fn login(&self, request: LoginInput) -> Result<LoginResult, AuthError>;
     async fn signup(&self, request: SignupInput) -> Result<UserId, AuthError>;
+    /// Check an access token. Tokens of sessions that were logged out are rejected.
     async fn verify_token(&self, token: &str) -> Result<UserId, AuthError>;
+    /// Exchange a refresh token for a new token pair.
+    /// The presented refresh token is invalidated. Presenting it again is treated as theft:
+    /// the whole token family is revoked and `AuthError::RefreshTokenReused` is returned.
     async fn refresh_token(&self, refresh_token: &str) -> Result<AuthTokens, AuthError>;
+
+    /// End the session the access token belongs to, including its refresh token.
+    /// Returns the owner of the session.
+    async fn logout(&self, access_token: &str) -> Result<UserId, AuthError>;
+
+    /// End every session of the user.
+    async fn logout_all(&self, user_id: &UserId) -> Result<(), AuthError>;
+
+    /// Issue a short-lived ticket that authenticates one chat WebSocket handshake.
+    async fn issue_ws_ticket(&self, user_id: &UserId) -> Result<WsTicket, AuthError>;
+
+    /// Consume a ticket. Unknown, expired and already redeemed tickets are rejected.
+    async fn redeem_ws_ticket(&self, ticket: &str) -> Result<UserId, AuthError>;
 }
\ No newline at end of file
@@ -0,0 +1,21 @@
+use std::fmt::Debug;
+use serde::{Deserialize, Serialize};
+use crate::auth::AuthError;
+use crate::domain::UserId;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credential {
+    pub user_id: UserId,
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[async_trait::async_trait]
+pub trait CredentialStore: Debug + Send + Sync {
+    /// Store a new credential.
+    /// Returns `AuthError::UsernameTaken` if the username is already registered.
+    async fn insert(&self, credential: Credential) -> Result<(), AuthError>;
+
+    /// Look up a credential by username.
+    async fn find(&self, username: &str) -> Result<Option<Credential>, AuthError>;
+}
\ No newline at end of file
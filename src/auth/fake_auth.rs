@@ -1,12 +1,21 @@
+use std::time::Duration;
+use dashmap::DashSet;
 use crate::auth::*;
 use crate::domain::UserId;
 
 #[derive(Debug)]
-pub struct FakeAuthService;
+pub struct FakeAuthService {
+    // Fake tokens can't tell sessions apart, so logging out revokes the user until the next login.
+    revoked_users: DashSet<UserId>,
+    ws_tickets: WsTicketStore,
+}
 
 impl FakeAuthService {
-    pub fn new() -> Self {
-        Self
+    pub fn new(ws_ticket_ttl: Duration) -> Self {
+        Self {
+            revoked_users: DashSet::new(),
+            ws_tickets: WsTicketStore::new(ws_ticket_ttl),
+        }
     }
 }
 
@@ -15,6 +24,7 @@ impl FakeAuthService {
 #[async_trait::async_trait]
 impl AuthService for FakeAuthService {
     async fn login(&self, request: LoginInput) -> Result<LoginResult, AuthError> {
+        self.revoked_users.remove(&get_fake_id(&request.username));
         Ok(LoginResult {
             user_id: get_fake_id(&request.username),
             auth_tokens: get_fake_token(&request.username),
@@ -26,20 +36,39 @@ impl AuthService for FakeAuthService {
     }
 
     async fn verify_token(&self, token: &str) -> Result<UserId, AuthError> {
-        if let Some(username) = token.strip_prefix("fake-access-token:") {
-            Ok(get_fake_id(&username))
-        } else {
-            Err(AuthError::InvalidToken)
+        match token.strip_prefix("fake-access-token:") {
+            Some(username) if !self.revoked_users.contains(&get_fake_id(username)) => Ok(get_fake_id(username)),
+            _ => Err(AuthError::InvalidToken),
         }
     }
 
     async fn refresh_token(&self, refresh_token: &str) -> Result<AuthTokens, AuthError> {
-        if let Some(username) = refresh_token.strip_prefix("fake-refresh-token:") {
-            Ok(get_fake_token(&username))
-        } else {
-            Err(AuthError::InvalidRefreshToken)
+        match refresh_token.strip_prefix("fake-refresh-token:") {
+            Some(username) if !self.revoked_users.contains(&get_fake_id(username)) => Ok(get_fake_token(username)),
+            _ => Err(AuthError::InvalidRefreshToken),
         }
     }
+
+    async fn logout(&self, access_token: &str) -> Result<UserId, AuthError> {
+        let user_id = self.verify_token(access_token).await?;
+        self.revoked_users.insert(user_id.clone());
+        self.ws_tickets.revoke(&user_id);
+        Ok(user_id)
+    }
+
+    async fn logout_all(&self, user_id: &UserId) -> Result<(), AuthError> {
+        self.revoked_users.insert(user_id.clone());
+        self.ws_tickets.revoke(user_id);
+        Ok(())
+    }
+
+    async fn issue_ws_ticket(&self, user_id: &UserId) -> Result<WsTicket, AuthError> {
+        Ok(self.ws_tickets.issue(user_id))
+    }
+
+    async fn redeem_ws_ticket(&self, ticket: &str) -> Result<UserId, AuthError> {
+        self.ws_tickets.redeem(ticket)
+    }
 }
 
 fn get_fake_id(username: &str) -> UserId {
@@ -49,7 +78,7 @@ fn get_fake_id(username: &str) -> UserId {
 fn get_fake_token(username: &str) -> AuthTokens {
     AuthTokens {
         access_token: format!("fake-access-token:{}", username),
-        access_expires_in: 1 * 60 * 60,  // 1 hour
+        access_expires_in: 60 * 60,  // 1 hour
         refresh_token: format!("fake-refresh-token:{}", username),
         refresh_expires_in: 7 * 24 * 60 * 60,  // 7 days
     }
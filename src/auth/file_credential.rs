@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use anyhow::anyhow;
+use tokio::sync::Mutex;
+use crate::auth::*;
+
+/// Keeps all credentials in memory and rewrites the whole JSON file on every signup.
+/// Good enough for a single instance with a modest number of users.
+#[derive(Debug)]
+pub struct FileCredentialStore {
+    path: PathBuf,
+    credentials: Mutex<HashMap<String, Credential>>,
+}
+
+impl FileCredentialStore {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let credentials = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<Credential>>(&bytes)?
+                .into_iter()
+                .map(|credential| (credential.username.clone(), credential))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(anyhow!("Failed to read credential file {:?}: {}", path, e)),
+        };
+
+        Ok(Self {
+            path,
+            credentials: Mutex::new(credentials),
+        })
+    }
+
+    async fn persist(&self, credentials: &HashMap<String, Credential>) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let bytes = serde_json::to_vec_pretty(&credentials.values().collect::<Vec<_>>())?;
+        // Write to a temporary file first so a crash never leaves a truncated store behind.
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialStore for FileCredentialStore {
+    async fn insert(&self, credential: Credential) -> Result<(), AuthError> {
+        let mut credentials = self.credentials.lock().await;
+        if credentials.contains_key(&credential.username) {
+            return Err(AuthError::UsernameTaken);
+        }
+
+        let username = credential.username.clone();
+        credentials.insert(username.clone(), credential);
+        if let Err(e) = self.persist(&credentials).await {
+            credentials.remove(&username);
+            return Err(AuthError::InternalError(e));
+        }
+        Ok(())
+    }
+
+    async fn find(&self, username: &str) -> Result<Option<Credential>, AuthError> {
+        Ok(self.credentials.lock().await.get(username).cloned())
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use anyhow::anyhow;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use crate::auth::*;
+use crate::domain::UserId;
+
+/// Keeps all token families in memory and rewrites the whole JSON file on every change,
+/// so logins survive a restart and revoked families stay revoked.
+/// Good enough for a single instance with a modest number of sessions.
+#[derive(Debug)]
+pub struct FileTokenFamilyStore {
+    path: PathBuf,
+    families: Mutex<HashMap<Uuid, TokenFamily>>,
+}
+
+impl FileTokenFamilyStore {
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let families = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<TokenFamily>>(&bytes)?
+                .into_iter()
+                .map(|family| (family.id, family))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(anyhow!("Failed to read token family file {:?}: {}", path, e)),
+        };
+
+        Ok(Self {
+            path,
+            families: Mutex::new(families),
+        })
+    }
+
+    async fn persist(&self, families: &HashMap<Uuid, TokenFamily>) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let bytes = serde_json::to_vec_pretty(&families.values().collect::<Vec<_>>())?;
+        // Write to a temporary file first so a crash never leaves a truncated store behind.
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+
+    /// Apply a change and persist it, or undo it if it can't be persisted.
+    async fn update(&self, change: impl FnOnce(&mut HashMap<Uuid, TokenFamily>) -> bool) -> Result<bool, AuthError> {
+        let mut families = self.families.lock().await;
+        let previous = families.clone();
+        if !change(&mut families) {
+            return Ok(false);
+        }
+        if let Err(e) = self.persist(&families).await {
+            *families = previous;
+            return Err(AuthError::InternalError(e));
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenFamilyStore for FileTokenFamilyStore {
+    async fn insert(&self, family: TokenFamily) -> Result<(), AuthError> {
+        self.update(|families| {
+            families.insert(family.id, family);
+            true
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn find(&self, id: &Uuid) -> Result<Option<TokenFamily>, AuthError> {
+        Ok(self.families.lock().await.get(id).cloned())
+    }
+
+    async fn rotate(&self, id: &Uuid, expected_jti: &Uuid, jti: Uuid, expires_at: u64) -> Result<bool, AuthError> {
+        self.update(|families| match families.get_mut(id) {
+            Some(family) if family.current_jti == *expected_jti => {
+                family.current_jti = jti;
+                family.expires_at = expires_at;
+                true
+            }
+            _ => false,
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &Uuid) -> Result<(), AuthError> {
+        self.update(|families| families.remove(id).is_some()).await?;
+        Ok(())
+    }
+
+    async fn remove_user(&self, user_id: &UserId) -> Result<(), AuthError> {
+        self.update(|families| {
+            let before = families.len();
+            families.retain(|_, family| family.user_id != *user_id);
+            families.len() != before
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_expired(&self, now: u64) -> Result<(), AuthError> {
+        self.update(|families| {
+            let before = families.len();
+            families.retain(|_, family| family.expires_at > now);
+            families.len() != before
+        })
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::anyhow;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, get_current_timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use crate::auth::*;
+use crate::domain::UserId;
+use crate::logger::*;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+impl Debug for JwtConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("secret", &"<redacted>")
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("access_token_ttl", &self.access_token_ttl)
+            .field("refresh_token_ttl", &self.refresh_token_ttl)
+            .field("ws_ticket_ttl", &self.ws_ticket_ttl)
+            .finish()
+    }
+}
+
+pub struct JwtConfig {
+    pub secret: String,
+    pub issuer: String,
+    pub audience: String,
+    pub access_token_ttl: u64,  // seconds
+    pub refresh_token_ttl: u64,  // seconds
+    pub ws_ticket_ttl: u64,  // seconds
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TokenType {
+    Access,
+    Refresh,
+}
+
+enum TokenError {
+    Expired,
+    Invalid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: Uuid,
+    iss: String,
+    aud: String,
+    iat: u64,
+    exp: u64,
+    jti: Uuid,
+    typ: TokenType,
+    /// Token family: every token pair rotated from the same login shares it.
+    fam: Uuid,
+}
+
+impl Debug for JwtAuthService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtAuthService")
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("access_token_ttl", &self.access_token_ttl)
+            .field("refresh_token_ttl", &self.refresh_token_ttl)
+            .field("credential_store", &self.credential_store)
+            .field("family_store", &self.family_store)
+            .field("hasher", &self.hasher)
+            .finish()
+    }
+}
+
+pub struct JwtAuthService {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    validation: Validation,
+    issuer: String,
+    audience: String,
+    access_token_ttl: u64,
+    refresh_token_ttl: u64,
+    credential_store: Arc<dyn CredentialStore>,
+    hasher: Argon2Hasher,
+    // Verified against when a username is unknown so that login takes the same time either way.
+    dummy_hash: String,
+    family_store: Arc<dyn TokenFamilyStore>,
+    sweeper_handle: JoinHandle<()>,
+    ws_tickets: WsTicketStore,
+}
+
+impl JwtAuthService {
+    pub fn new(
+        config: &JwtConfig,
+        password_config: &PasswordConfig,
+        credential_store: Arc<dyn CredentialStore>,
+        family_store: Arc<dyn TokenFamilyStore>,
+    ) -> anyhow::Result<Self> {
+        if config.secret.is_empty() {
+            return Err(anyhow!("JWT secret must not be empty"));
+        }
+
+        let hasher = Argon2Hasher::new(password_config)?;
+        let dummy_hash = hasher.hash_blocking("dummy-password")?;
+        let sweeper_handle = tokio::spawn(sweeper(family_store.clone()));
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_audience(&[&config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        Ok(Self {
+            encoding_key: EncodingKey::from_secret(config.secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(config.secret.as_bytes()),
+            validation,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            access_token_ttl: config.access_token_ttl,
+            refresh_token_ttl: config.refresh_token_ttl,
+            credential_store,
+            hasher,
+            dummy_hash,
+            family_store,
+            sweeper_handle,
+            ws_tickets: WsTicketStore::new(Duration::from_secs(config.ws_ticket_ttl)),
+        })
+    }
+
+    fn encode(&self, user_id: &UserId, family: Uuid, jti: Uuid, typ: TokenType, ttl: u64) -> Result<String, AuthError> {
+        let now = get_current_timestamp();
+        let claims = Claims {
+            sub: user_id.0,
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now,
+            exp: now + ttl,
+            jti,
+            typ,
+            fam: family,
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|e| AuthError::InternalError(anyhow!(e)))
+    }
+
+    /// Decode a token and check its signature, issuer, audience, expiry and type.
+    fn decode(&self, token: &str, typ: TokenType) -> Result<Claims, TokenError> {
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding_key, &self.validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => TokenError::Expired,
+                _ => TokenError::Invalid,
+            })?
+            .claims;
+        if claims.typ != typ {
+            return Err(TokenError::Invalid);
+        }
+        Ok(claims)
+    }
+
+    fn issue_tokens(&self, user_id: &UserId, family: Uuid, refresh_jti: Uuid) -> Result<AuthTokens, AuthError> {
+        Ok(AuthTokens {
+            access_token: self.encode(user_id, family, Uuid::new_v4(), TokenType::Access, self.access_token_ttl)?,
+            access_expires_in: self.access_token_ttl,
+            refresh_token: self.encode(user_id, family, refresh_jti, TokenType::Refresh, self.refresh_token_ttl)?,
+            refresh_expires_in: self.refresh_token_ttl,
+        })
+    }
+
+    async fn verify_access(&self, token: &str) -> Result<Claims, AuthError> {
+        let claims = match self.decode(token, TokenType::Access) {
+            Ok(claims) => claims,
+            Err(TokenError::Expired) => return Err(AuthError::TokenExpired),
+            Err(TokenError::Invalid) => return Err(AuthError::InvalidToken),
+        };
+        // Access tokens die with their family, e.g. after logout or refresh token reuse.
+        match self.family_store.find(&claims.fam).await? {
+            Some(_) => Ok(claims),
+            None => Err(AuthError::InvalidToken),
+        }
+    }
+
+    /// Start a new token family for a fresh login.
+    async fn start_family(&self, user_id: &UserId) -> Result<AuthTokens, AuthError> {
+        let family = Uuid::new_v4();
+        let refresh_jti = Uuid::new_v4();
+        self.family_store
+            .insert(TokenFamily {
+                id: family,
+                user_id: user_id.clone(),
+                current_jti: refresh_jti,
+                expires_at: get_current_timestamp() + self.refresh_token_ttl,
+            })
+            .await?;
+        self.issue_tokens(user_id, family, refresh_jti)
+    }
+
+    /// Replace the family's current refresh token, or revoke the family if `claims` is stale.
+    async fn rotate_family(&self, claims: &Claims) -> Result<AuthTokens, AuthError> {
+        let user_id = UserId(claims.sub);
+        let Some(family) = self.family_store.find(&claims.fam).await? else {
+            return Err(AuthError::InvalidRefreshToken);
+        };
+        if family.user_id != user_id {
+            return Err(AuthError::InvalidRefreshToken);
+        }
+        let refresh_jti = Uuid::new_v4();
+        let expires_at = get_current_timestamp() + self.refresh_token_ttl;
+        // Also fails if a concurrent refresh with the same token got there first, which is reuse just as well.
+        if !self.family_store.rotate(&claims.fam, &claims.jti, refresh_jti, expires_at).await? {
+            self.family_store.remove(&claims.fam).await?;
+            warn!("Refresh token reuse detected, revoked token family {} of {:?}", claims.fam, user_id);
+            return Err(AuthError::RefreshTokenReused);
+        }
+        self.issue_tokens(&user_id, claims.fam, refresh_jti)
+    }
+}
+
+impl Drop for JwtAuthService {
+    fn drop(&mut self) {
+        self.sweeper_handle.abort();
+    }
+}
+
+async fn sweeper(family_store: Arc<dyn TokenFamilyStore>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = family_store.remove_expired(get_current_timestamp()).await {
+            warn!("Failed to sweep expired token families: {}", e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuthService for JwtAuthService {
+    async fn login(&self, request: LoginInput) -> Result<LoginResult, AuthError> {
+        let credential = self.credential_store.find(&request.username).await?;
+        let password_hash = credential
+            .as_ref()
+            .map_or_else(|| self.dummy_hash.clone(), |credential| credential.password_hash.clone());
+        let is_valid = self.hasher.verify(request.password, password_hash).await?;
+
+        match credential {
+            Some(credential) if is_valid => {
+                let auth_tokens = self.start_family(&credential.user_id).await?;
+                Ok(LoginResult {
+                    user_id: credential.user_id,
+                    auth_tokens,
+                })
+            }
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    async fn signup(&self, request: SignupInput) -> Result<UserId, AuthError> {
+        // Cheap early check so that taken usernames don't cost a hash.
+        // The store still enforces uniqueness on insert.
+        if self.credential_store.find(&request.username).await?.is_some() {
+            return Err(AuthError::UsernameTaken);
+        }
+
+        let password_hash = self.hasher.hash(request.password).await?;
+        let user_id = UserId(uuid::Uuid::new_v4());
+        self.credential_store
+            .insert(Credential {
+                user_id: user_id.clone(),
+                username: request.username,
+                password_hash,
+            })
+            .await?;
+        Ok(user_id)
+    }
+
+    async fn verify_token(&self, token: &str) -> Result<UserId, AuthError> {
+        self.verify_access(token).await.map(|claims| UserId(claims.sub))
+    }
+
+    async fn refresh_token(&self, refresh_token: &str) -> Result<AuthTokens, AuthError> {
+        match self.decode(refresh_token, TokenType::Refresh) {
+            Ok(claims) => self.rotate_family(&claims).await,
+            Err(TokenError::Expired) => Err(AuthError::RefreshTokenExpired),
+            Err(TokenError::Invalid) => Err(AuthError::InvalidRefreshToken),
+        }
+    }
+    async fn logout(&self, access_token: &str) -> Result<UserId, AuthError> {
+        let claims = self.verify_access(access_token).await?;
+        self.family_store.remove(&claims.fam).await?;
+        let user_id = UserId(claims.sub);
+        self.ws_tickets.revoke(&user_id);
+        Ok(user_id)
+    }
+
+    async fn logout_all(&self, user_id: &UserId) -> Result<(), AuthError> {
+        self.family_store.remove_user(user_id).await?;
+        self.ws_tickets.revoke(user_id);
+        Ok(())
+    }
+
+    async fn issue_ws_ticket(&self, user_id: &UserId) -> Result<WsTicket, AuthError> {
+        Ok(self.ws_tickets.issue(user_id))
+    }
+
+    async fn redeem_ws_ticket(&self, ticket: &str) -> Result<UserId, AuthError> {
+        self.ws_tickets.redeem(ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> JwtAuthService {
+        let config = JwtConfig {
+            secret: "test-secret".to_string(),
+            issuer: "test".to_string(),
+            audience: "test".to_string(),
+            access_token_ttl: 60,
+            refresh_token_ttl: 60,
+            ws_ticket_ttl: 30,
+        };
+        // As cheap as argon2 allows, the tests never hash a password themselves.
+        let password_config = PasswordConfig {
+            memory_cost: 8,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        JwtAuthService::new(
+            &config,
+            &password_config,
+            Arc::new(MemoryCredentialStore::new()),
+            Arc::new(MemoryTokenFamilyStore::new()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_the_tokens() {
+        let service = service();
+        let user_id = UserId(Uuid::new_v4());
+        let tokens = service.start_family(&user_id).await.unwrap();
+
+        let rotated = service.refresh_token(&tokens.refresh_token).await.unwrap();
+        assert_ne!(rotated.refresh_token, tokens.refresh_token);
+        assert_eq!(service.verify_token(&rotated.access_token).await.unwrap(), user_id);
+        // Access tokens of the family stay valid until they expire.
+        assert_eq!(service.verify_token(&tokens.access_token).await.unwrap(), user_id);
+        assert!(service.refresh_token(&rotated.refresh_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_the_family() {
+        let service = service();
+        let user_id = UserId(Uuid::new_v4());
+        let tokens = service.start_family(&user_id).await.unwrap();
+        let rotated = service.refresh_token(&tokens.refresh_token).await.unwrap();
+
+        let reused = service.refresh_token(&tokens.refresh_token).await;
+        assert!(matches!(reused, Err(AuthError::RefreshTokenReused)));
+        // The legitimate holder of the rotated token is logged out too.
+        let rotated_refresh = service.refresh_token(&rotated.refresh_token).await;
+        assert!(matches!(rotated_refresh, Err(AuthError::InvalidRefreshToken)));
+    }
+
+    #[tokio::test]
+    async fn access_token_dies_with_its_family() {
+        let service = service();
+        let user_id = UserId(Uuid::new_v4());
+        let tokens = service.start_family(&user_id).await.unwrap();
+        let other = service.start_family(&user_id).await.unwrap();
+        let rotated = service.refresh_token(&tokens.refresh_token).await.unwrap();
+        let _ = service.refresh_token(&tokens.refresh_token).await;
+
+        for access_token in [&tokens.access_token, &rotated.access_token] {
+            assert!(matches!(service.verify_token(access_token).await, Err(AuthError::InvalidToken)));
+        }
+        // Other logins of the user are left alone.
+        assert_eq!(service.verify_token(&other.access_token).await.unwrap(), user_id);
+    }
+
+    #[tokio::test]
+    async fn expired_refresh_token_is_rejected() {
+        let service = service();
+        let user_id = UserId(Uuid::new_v4());
+        let tokens = service.start_family(&user_id).await.unwrap();
+        // Still the family's current token, only past its expiry.
+        let issued = service.decode(&tokens.refresh_token, TokenType::Refresh).ok().unwrap();
+        let now = get_current_timestamp();
+        let claims = Claims {
+            iat: now - 120,
+            exp: now - 60,
+            ..issued
+        };
+        let expired = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &service.encoding_key).unwrap();
+
+        let refreshed = service.refresh_token(&expired).await;
+        assert!(matches!(refreshed, Err(AuthError::RefreshTokenExpired)));
+    }
+}
@@ -0,0 +1,33 @@
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use crate::auth::*;
+
+#[derive(Debug)]
+pub struct MemoryCredentialStore {
+    credentials: DashMap<String, Credential>,
+}
+
+impl MemoryCredentialStore {
+    pub fn new() -> Self {
+        Self {
+            credentials: DashMap::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialStore for MemoryCredentialStore {
+    async fn insert(&self, credential: Credential) -> Result<(), AuthError> {
+        match self.credentials.entry(credential.username.clone()) {
+            Entry::Occupied(_) => Err(AuthError::UsernameTaken),
+            Entry::Vacant(entry) => {
+                entry.insert(credential);
+                Ok(())
+            }
+        }
+    }
+
+    async fn find(&self, username: &str) -> Result<Option<Credential>, AuthError> {
+        Ok(self.credentials.get(username).map(|credential| credential.clone()))
+    }
+}
@@ -0,0 +1,56 @@
+use dashmap::DashMap;
+use uuid::Uuid;
+use crate::auth::*;
+use crate::domain::UserId;
+
+#[derive(Debug)]
+pub struct MemoryTokenFamilyStore {
+    families: DashMap<Uuid, TokenFamily>,
+}
+
+impl MemoryTokenFamilyStore {
+    pub fn new() -> Self {
+        Self {
+            families: DashMap::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenFamilyStore for MemoryTokenFamilyStore {
+    async fn insert(&self, family: TokenFamily) -> Result<(), AuthError> {
+        self.families.insert(family.id, family);
+        Ok(())
+    }
+
+    async fn find(&self, id: &Uuid) -> Result<Option<TokenFamily>, AuthError> {
+        Ok(self.families.get(id).map(|family| family.clone()))
+    }
+
+    async fn rotate(&self, id: &Uuid, expected_jti: &Uuid, jti: Uuid, expires_at: u64) -> Result<bool, AuthError> {
+        let Some(mut family) = self.families.get_mut(id) else {
+            return Ok(false);
+        };
+        if family.current_jti != *expected_jti {
+            return Ok(false);
+        }
+        family.current_jti = jti;
+        family.expires_at = expires_at;
+        Ok(true)
+    }
+
+    async fn remove(&self, id: &Uuid) -> Result<(), AuthError> {
+        self.families.remove(id);
+        Ok(())
+    }
+
+    async fn remove_user(&self, user_id: &UserId) -> Result<(), AuthError> {
+        self.families.retain(|_, family| family.user_id != *user_id);
+        Ok(())
+    }
+
+    async fn remove_expired(&self, now: u64) -> Result<(), AuthError> {
+        self.families.retain(|_, family| family.expires_at > now);
+        Ok(())
+    }
+}
@@ -1,5 +1,23 @@
 mod auth;
+mod credential;
 mod fake_auth;
+mod file_credential;
+mod file_token_family;
+mod jwt_auth;
+mod memory_credential;
+mod memory_token_family;
+mod password;
+mod token_family;
+mod ws_ticket;
 
 pub use auth::*;
-pub use fake_auth::*;
\ No newline at end of file
+pub use credential::*;
+pub use fake_auth::*;
+pub use file_credential::*;
+pub use file_token_family::*;
+pub use jwt_auth::*;
+pub use memory_credential::*;
+pub use memory_token_family::*;
+pub use password::*;
+pub use token_family::*;
+pub use ws_ticket::*;
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use anyhow::anyhow;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use crate::auth::AuthError;
+
+#[derive(Debug)]
+pub struct PasswordConfig {
+    pub memory_cost: u32,  // KiB
+    pub time_cost: u32,  // iterations
+    pub parallelism: u32,  // lanes
+}
+
+impl Debug for Argon2Hasher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Argon2Hasher")
+            .field("params", self.argon2.params())
+            .finish()
+    }
+}
+
+/// Hashes and verifies passwords with Argon2id.
+/// The work is CPU-bound, so the async methods run it on the blocking thread pool.
+#[derive(Clone)]
+pub struct Argon2Hasher {
+    argon2: Argon2<'static>,
+}
+
+impl Argon2Hasher {
+    pub fn new(config: &PasswordConfig) -> anyhow::Result<Self> {
+        let params = Params::new(config.memory_cost, config.time_cost, config.parallelism, None)
+            .map_err(|e| anyhow!("Invalid argon2 parameters: {}", e))?;
+        Ok(Self {
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        })
+    }
+
+    pub async fn hash(&self, password: String) -> Result<String, AuthError> {
+        let hasher = self.clone();
+        tokio::task::spawn_blocking(move || hasher.hash_blocking(&password))
+            .await
+            .map_err(|e| AuthError::InternalError(anyhow!(e)))?
+    }
+
+    /// Returns `Ok(false)` on a mismatch. The parameters stored in `password_hash` are used,
+    /// so hashes created before a cost change still verify.
+    pub async fn verify(&self, password: String, password_hash: String) -> Result<bool, AuthError> {
+        let hasher = self.clone();
+        tokio::task::spawn_blocking(move || hasher.verify_blocking(&password, &password_hash))
+            .await
+            .map_err(|e| AuthError::InternalError(anyhow!(e)))?
+    }
+
+    pub fn hash_blocking(&self, password: &str) -> Result<String, AuthError> {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        let password_hash = self.argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| AuthError::InternalError(anyhow!("Failed to hash password: {}", e)))?;
+        Ok(password_hash.to_string())
+    }
+
+    fn verify_blocking(&self, password: &str, password_hash: &str) -> Result<bool, AuthError> {
+        let password_hash = PasswordHash::new(password_hash)
+            .map_err(|e| AuthError::InternalError(anyhow!("Malformed password hash: {}", e)))?;
+        match self.argon2.verify_password(password.as_bytes(), &password_hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(AuthError::InternalError(anyhow!("Failed to verify password: {}", e))),
+        }
+    }
+}
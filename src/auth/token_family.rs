@@ -0,0 +1,37 @@
+use std::fmt::Debug;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::auth::AuthError;
+use crate::domain::UserId;
+
+/// Server-side state of a refresh token family: every token pair rotated from the same login.
+/// Only the most recently issued refresh token (`current_jti`) may be exchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenFamily {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub current_jti: Uuid,
+    pub expires_at: u64,  // unix seconds
+}
+
+#[async_trait::async_trait]
+pub trait TokenFamilyStore: Debug + Send + Sync {
+    /// Store a new family.
+    async fn insert(&self, family: TokenFamily) -> Result<(), AuthError>;
+
+    /// Look up a family that hasn't been revoked.
+    async fn find(&self, id: &Uuid) -> Result<Option<TokenFamily>, AuthError>;
+
+    /// Replace the family's current refresh token, if it still is `expected_jti`.
+    /// Returns whether it was replaced.
+    async fn rotate(&self, id: &Uuid, expected_jti: &Uuid, jti: Uuid, expires_at: u64) -> Result<bool, AuthError>;
+
+    /// Revoke a family, together with the tokens issued in it.
+    async fn remove(&self, id: &Uuid) -> Result<(), AuthError>;
+
+    /// Revoke all families of the user.
+    async fn remove_user(&self, user_id: &UserId) -> Result<(), AuthError>;
+
+    /// Forget families whose last refresh token expired by `now`.
+    async fn remove_expired(&self, now: u64) -> Result<(), AuthError>;
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use crate::auth::*;
+use crate::domain::UserId;
+
+/// Single-use tickets that let browsers authenticate a WebSocket handshake,
+/// where they cannot set an `Authorization` header.
+#[derive(Debug)]
+pub struct WsTicketStore {
+    tickets: DashMap<String, (UserId, Instant)>,
+    ttl: Duration,
+}
+
+impl WsTicketStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            tickets: DashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn issue(&self, user_id: &UserId) -> WsTicket {
+        // Tickets live for seconds, so dropping the expired ones here keeps the map small.
+        let now = Instant::now();
+        self.tickets.retain(|_, (_, expire_at)| *expire_at > now);
+
+        let ticket = uuid::Uuid::new_v4().simple().to_string();
+        self.tickets.insert(ticket.clone(), (user_id.clone(), now + self.ttl));
+        WsTicket {
+            ticket,
+            expires_in: self.ttl.as_secs(),
+        }
+    }
+
+    pub fn redeem(&self, ticket: &str) -> Result<UserId, AuthError> {
+        match self.tickets.remove(ticket) {
+            Some((_, (user_id, expire_at))) if expire_at > Instant::now() => Ok(user_id),
+            _ => Err(AuthError::InvalidToken),
+        }
+    }
+
+    /// Drops every outstanding ticket issued to `user_id`, so a revoked session can't still open a socket.
+    pub fn revoke(&self, user_id: &UserId) {
+        self.tickets.retain(|_, (owner, _)| owner != user_id);
+    }
+}
@@ -4,9 +4,13 @@ use uuid::Uuid;
 
 fn main() {
     let c2s = ClientToServer::Send(SendMessage {
+        correlation_id: Some("local-1".to_string()),
+        client_message_id: None,
+        attachment_ids: vec![],
         content: ChatContent {
             conversation_id: ConversationId(Uuid::nil()),
             content: "Hello".to_string(),
+            reply_to: None,
         },
     });
     println!("{}", serde_json::to_string(&c2s).unwrap());
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::fmt::Debug;
 use chrono::{DateTime, Utc};
 use thiserror::Error;
 
@@ -26,11 +26,12 @@ pub struct ValidationInput {
 }
 
 #[async_trait::async_trait]
-pub trait CaptchaService: Send + Sync {
+pub trait CaptchaService: Debug + Send + Sync {
     /// Generate a captcha image that will expire after the given duration.
     async fn generate(&self) -> Result<CaptchaResult, CaptchaError>;
 
     /// Validate the user's answer to a captcha.
-    /// Returns Ok(true) if valid, Ok(false) if invalid, Err if internal error.
+    /// Returns `CaptchaError::Mismatch` for a wrong answer and `CaptchaError::NotFound`
+    /// for an unknown, expired or already used captcha.
     async fn validate(&self, input: ValidationInput) -> Result<(), CaptchaError>;
 }
\ No newline at end of file
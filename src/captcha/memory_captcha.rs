@@ -0,0 +1,91 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use tokio::task::JoinHandle;
+use crate::captcha::*;
+use crate::logger::*;
+
+struct PendingCaptcha {
+    answer: String,
+    expire_at: DateTime<Utc>,
+}
+
+impl Debug for MemoryCaptchaService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryCaptchaService")
+            .field("config", &self.config)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+/// Keeps answers in process memory, so it only works for a single server instance.
+pub struct MemoryCaptchaService {
+    config: CaptchaConfig,
+    pending: Arc<DashMap<uuid::Uuid, PendingCaptcha>>,
+    sweeper_handle: JoinHandle<()>,
+}
+
+impl MemoryCaptchaService {
+    pub fn new(config: CaptchaConfig, sweep_interval: Duration) -> Self {
+        let pending = Arc::new(DashMap::new());
+        let sweeper_handle = tokio::spawn(sweeper(pending.clone(), sweep_interval));
+        Self {
+            config,
+            pending,
+            sweeper_handle,
+        }
+    }
+}
+
+impl Drop for MemoryCaptchaService {
+    fn drop(&mut self) {
+        self.sweeper_handle.abort();
+    }
+}
+
+async fn sweeper(pending: Arc<DashMap<uuid::Uuid, PendingCaptcha>>, sweep_interval: Duration) {
+    // `interval` panics on a zero period.
+    let mut interval = tokio::time::interval(sweep_interval.max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let before = pending.len();
+        pending.retain(|_, captcha| captcha.expire_at > now);
+        debug!("Purged {} expired captchas", before.saturating_sub(pending.len()));
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaService for MemoryCaptchaService {
+    async fn generate(&self) -> Result<CaptchaResult, CaptchaError> {
+        let rendered = render_captcha(&self.config).await?;
+        let id = uuid::Uuid::new_v4();
+        let expire_at = Utc::now() + Duration::from_secs(self.config.ttl);
+        self.pending.insert(id, PendingCaptcha {
+            answer: rendered.answer,
+            expire_at,
+        });
+
+        Ok(CaptchaResult {
+            id,
+            image_base64: rendered.image_base64,
+            expire_at,
+        })
+    }
+
+    async fn validate(&self, input: ValidationInput) -> Result<(), CaptchaError> {
+        // Every captcha gets exactly one attempt, right or wrong.
+        let (_, captcha) = self.pending.remove(&input.id).ok_or(CaptchaError::NotFound)?;
+        if captcha.expire_at <= Utc::now() {
+            return Err(CaptchaError::NotFound);
+        }
+        if !answer_matches(&captcha.answer, &input.answer) {
+            return Err(CaptchaError::Mismatch);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,11 @@
 mod captcha;
 mod fake_captcha;
+mod memory_captcha;
+mod redis_captcha;
+mod render;
 
 pub use captcha::*;
-pub use fake_captcha::*;
\ No newline at end of file
+pub use fake_captcha::*;
+pub use memory_captcha::*;
+pub use redis_captcha::*;
+pub use render::*;
\ No newline at end of file
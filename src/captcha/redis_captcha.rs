@@ -0,0 +1,104 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+use anyhow::anyhow;
+use chrono::Utc;
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+use tokio::sync::OnceCell;
+use crate::captcha::*;
+
+impl Debug for RedisConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisConfig")
+            .field("url", &"<redacted>")
+            .field("key_prefix", &self.key_prefix)
+            .finish()
+    }
+}
+
+pub struct RedisConfig {
+    pub url: String,
+    pub key_prefix: String,
+}
+
+impl Debug for RedisCaptchaService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisCaptchaService")
+            .field("config", &self.config)
+            .field("key_prefix", &self.key_prefix)
+            .field("connected", &self.connection.initialized())
+            .finish()
+    }
+}
+
+/// Stores answers in Redis with native TTLs, so any server instance can validate any captcha.
+/// Requires Redis 6.2 or later for `GETDEL`.
+pub struct RedisCaptchaService {
+    config: CaptchaConfig,
+    key_prefix: String,
+    client: redis::Client,
+    // Connected on first use so that the server can start while Redis is still coming up.
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisCaptchaService {
+    pub fn new(config: CaptchaConfig, redis_config: &RedisConfig) -> anyhow::Result<Self> {
+        let client = redis::Client::open(redis_config.url.as_str())?;
+        Ok(Self {
+            config,
+            key_prefix: redis_config.key_prefix.clone(),
+            client,
+            connection: OnceCell::new(),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, CaptchaError> {
+        let connection = self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .map_err(|e| CaptchaError::InternalError(anyhow!(e)))?;
+        Ok(connection.clone())
+    }
+
+    fn key(&self, id: &uuid::Uuid) -> String {
+        format!("{}{}", self.key_prefix, id)
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaService for RedisCaptchaService {
+    async fn generate(&self) -> Result<CaptchaResult, CaptchaError> {
+        let rendered = render_captcha(&self.config).await?;
+        let id = uuid::Uuid::new_v4();
+        let expire_at = Utc::now() + Duration::from_secs(self.config.ttl);
+
+        let mut connection = self.connection().await?;
+        connection
+            .set_ex::<_, _, ()>(self.key(&id), rendered.answer, self.config.ttl)
+            .await
+            .map_err(|e| CaptchaError::InternalError(anyhow!(e)))?;
+
+        Ok(CaptchaResult {
+            id,
+            image_base64: rendered.image_base64,
+            expire_at,
+        })
+    }
+
+    async fn validate(&self, input: ValidationInput) -> Result<(), CaptchaError> {
+        // GETDEL consumes the answer atomically, so each captcha gets exactly one attempt
+        // even when two instances race on it. Expired keys are already gone.
+        let mut connection = self.connection().await?;
+        let answer: Option<String> = connection
+            .get_del(self.key(&input.id))
+            .await
+            .map_err(|e| CaptchaError::InternalError(anyhow!(e)))?;
+
+        match answer {
+            Some(answer) if answer_matches(&answer, &input.answer) => Ok(()),
+            Some(_) => Err(CaptchaError::Mismatch),
+            None => Err(CaptchaError::NotFound),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::anyhow;
+use captcha_rs::CaptchaBuilder;
+use crate::captcha::CaptchaError;
+
+#[derive(Debug, Clone)]
+pub struct CaptchaConfig {
+    pub ttl: u64,  // seconds
+    pub length: usize,
+    pub width: u32,
+    pub height: u32,
+    pub complexity: u32,  // 1 to 10
+}
+
+pub struct RenderedCaptcha {
+    pub answer: String,
+    pub image_base64: String,
+}
+
+/// Render a random captcha on the blocking thread pool.
+pub async fn render_captcha(config: &CaptchaConfig) -> Result<RenderedCaptcha, CaptchaError> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        let captcha = CaptchaBuilder::new()
+            .length(config.length)
+            .width(config.width)
+            .height(config.height)
+            .complexity(config.complexity)
+            .build();
+        // `to_base64` returns a data URL, but clients expect bare base64 like the fake backend sends.
+        let data_url = captcha.to_base64();
+        let image_base64 = data_url
+            .split_once(',')
+            .map_or(data_url.as_str(), |(_, base64)| base64)
+            .to_string();
+        RenderedCaptcha {
+            answer: captcha.text,
+            image_base64,
+        }
+    })
+    .await
+    .map_err(|e| CaptchaError::InternalError(anyhow!(e)))
+}
+
+/// Answers are compared case-insensitively, since some glyphs are hard to tell apart.
+pub fn answer_matches(expected: &str, answer: &str) -> bool {
+    expected.eq_ignore_ascii_case(answer.trim())
+}
@@ -1,10 +1,63 @@
+use crate::attachment::AttachmentError;
+use crate::chat::ErrorCode;
 use crate::domain::UserId;
 use futures_util::stream::{SplitSink, SplitStream};
 use thiserror::Error;
 use warp::ws::{Message, WebSocket};
 
 #[derive(Debug, Error)]
-pub enum ChatError {}
+pub enum ChatError {
+    #[error("Malformed message: {0}")]
+    ParseError(serde_json::Error),
+    #[error("Only text frames are supported")]
+    UnsupportedFrame,
+    #[error("Not a member of this conversation")]
+    NotAMember,
+    #[error("Message not found")]
+    MessageNotFound,
+    #[error("Only the sender or an admin may change this message")]
+    Forbidden,
+    #[error("Reactions must be a single emoji of at most {0} bytes")]
+    InvalidReaction(usize),
+    #[error("A message may have at most {0} different reactions")]
+    TooManyReactions(usize),
+    #[error("Invalid attachment: {0}")]
+    InvalidAttachment(AttachmentError),
+    #[error("A message may have at most {0} attachments")]
+    TooManyAttachments(usize),
+    #[error("Too many messages, slow down")]
+    RateLimited,
+    #[error("Message exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("Internal error: {0}")]
+    InternalError(#[from] anyhow::Error),
+}
+
+impl ChatError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ChatError::ParseError(_) => ErrorCode::ParseError,
+            ChatError::UnsupportedFrame => ErrorCode::UnsupportedFrame,
+            ChatError::NotAMember => ErrorCode::NotAMember,
+            ChatError::MessageNotFound => ErrorCode::NotFound,
+            ChatError::Forbidden => ErrorCode::Forbidden,
+            ChatError::InvalidReaction(_) | ChatError::TooManyReactions(_) => ErrorCode::InvalidReaction,
+            ChatError::InvalidAttachment(_) | ChatError::TooManyAttachments(_) => ErrorCode::InvalidAttachment,
+            ChatError::RateLimited => ErrorCode::RateLimited,
+            ChatError::TooLarge(_) => ErrorCode::TooLarge,
+            ChatError::InternalError(_) => ErrorCode::InternalError,
+        }
+    }
+}
+
+impl From<AttachmentError> for ChatError {
+    fn from(e: AttachmentError) -> Self {
+        match e {
+            AttachmentError::InternalError(e) => ChatError::InternalError(e),
+            e => ChatError::InvalidAttachment(e),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 pub trait ChatService: Send + Sync {
@@ -14,4 +67,7 @@ pub trait ChatService: Send + Sync {
         from_user: SplitStream<WebSocket>,
         user_id: UserId,
     ) -> Result<(), anyhow::Error>;
+
+    /// Close every chat connection of the user, e.g. after their session was revoked.
+    async fn disconnect_user(&self, user_id: &UserId) -> Result<(), anyhow::Error>;
 }
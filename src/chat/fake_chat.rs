@@ -1,96 +1,838 @@
+use crate::attachment::*;
 use crate::chat::*;
-use crate::domain::UserId;
+use crate::domain::{ConnectionId, ConversationId, MessageId, UserId};
 use crate::logger::*;
 use crate::user::*;
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender, channel};
 use tokio::task::JoinHandle;
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
+/// Enough for emoji built from several code points, like flags and families.
+const MAX_EMOJI_SIZE: usize = 32;  // bytes
+
+/// Different emoji a message may collect, however many users react with each.
+const MAX_REACTIONS: usize = 20;
+
+const MAX_ATTACHMENTS: usize = 10;
+
+#[derive(Debug)]
+pub struct ChatConfig {
+    pub history_page_size: usize,
+    pub offline_queue_capacity: usize,
+    pub dedupe_window: u64,  // seconds
+    /// Max size of a sent message's content.
+    pub max_message_size: usize,  // bytes
+    /// Each user may send `rate_limit_messages` per `rate_limit_window`, across all their devices.
+    pub rate_limit_messages: u32,
+    pub rate_limit_window: u64,  // seconds
+    /// Messages of one conversation always go to the same worker, so they are handled in order.
+    pub dispatcher_workers: usize,
+    /// Client messages waiting for each worker. When full, reading from the sending socket pauses.
+    pub dispatcher_capacity: usize,
+    /// Frames waiting to be written to one connection, handled by `overflow_policy` when full.
+    pub outbox_capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub ping_interval: u64,  // seconds
+    /// Unanswered pings in a row before the connection is closed with `CLOSE_HEARTBEAT_TIMEOUT`.
+    pub max_missed_pongs: u32,
+    /// Time without a message from the client before the connection is closed with `CLOSE_IDLE_TIMEOUT`.
+    /// Pongs don't count as messages.
+    pub idle_timeout: u64,  // seconds, 0 disables
+    /// How long a `typing: true` lasts without being repeated.
+    pub typing_timeout: u64,  // seconds
+}
+
 pub struct ClientRecord {
     pub user_id: UserId,
-    pub to_sender: UnboundedSender<Message>,
+    pub outbox: Arc<Outbox>,
     pub watcher_handle: JoinHandle<Result<()>>,
 }
 
+/// The outcome of a send with a client message id, kept to answer retries.
+struct RecentSend {
+    message_id: MessageId,
+    sent_at: DateTime<Utc>,
+    expire_at: Instant,
+}
+
+/// Messages a user sent in the current rate limit window.
+struct RateWindow {
+    started_at: Instant,
+    count: u32,
+}
+
 struct WithSender<T> {
     pub sender: UserId,
+    pub connection_id: ConnectionId,
     pub body: T,
 }
 
+/// The dispatcher workers, picked by conversation, or by sender for messages without one.
+#[derive(Clone)]
+struct Dispatchers {
+    workers: Arc<[Sender<WithSender<ClientToServer>>]>,
+}
+
+impl Dispatchers {
+    async fn send(&self, message: WithSender<ClientToServer>) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        match message.body.conversation_id() {
+            Some(conversation_id) => conversation_id.hash(&mut hasher),
+            None => message.sender.hash(&mut hasher),
+        }
+        let worker = &self.workers[hasher.finish() as usize % self.workers.len()];
+        worker.send(message).await.map_err(|_| anyhow!("Dispatcher stopped"))
+    }
+}
+
+/// State shared by the service, the dispatcher workers and the per-connection tasks.
+struct ChatState {
+    config: ChatConfig,
+    /// Every open connection of each online user. Users without connections have no entry.
+    online_users: DashMap<UserId, HashMap<ConnectionId, ClientRecord>>,
+    /// Messages for users who are offline, oldest first, delivered when they join.
+    /// A user's entry is locked before their `online_users` entry, never the other way round.
+    offline_queues: DashMap<UserId, VecDeque<QueuedMessage>>,
+    recent_sends: DashMap<(UserId, String), RecentSend>,
+    rate_windows: DashMap<UserId, RateWindow>,
+    /// Status of each online user, changed under their `online_users` entry.
+    presence: DashMap<UserId, PresenceStatus>,
+    /// When each user's typing in a conversation expires.
+    typing: DashMap<(ConversationId, UserId), Instant>,
+    /// Messages dropped from full offline queues, and from the outboxes of closed connections.
+    dropped_messages: AtomicU64,
+    user_service: Arc<dyn UserService>,
+    message_store: Arc<dyn MessageStore>,
+    attachment_service: Arc<dyn AttachmentService>,
+}
+
 impl Debug for FakeChatService {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("FakeChatService")
-            .field("online_users", &self.online_users.len())
+            .field("config", &self.state.config)
+            .field("online_users", &self.state.online_users.len())
+            .field("offline_queues", &self.state.offline_queues.len())
+            .field("recent_sends", &self.state.recent_sends.len())
+            .field("dropped_messages", &self.state.dropped_messages.load(Ordering::Relaxed))
+            .field("message_store", &self.state.message_store)
+            .field("attachment_service", &self.state.attachment_service)
             .finish()
     }
 }
 
 pub struct FakeChatService {
-    user_service: Arc<dyn UserService>,
-    online_users: Arc<DashMap<UserId, ClientRecord>>,
-    to_dispatcher: UnboundedSender<WithSender<ClientToServer>>,
-    dispatcher_handle: JoinHandle<()>,
+    state: Arc<ChatState>,
+    dispatchers: Dispatchers,
+    dispatcher_handles: Vec<JoinHandle<()>>,
+    sweeper_handle: JoinHandle<()>,
+    typing_handle: JoinHandle<()>,
 }
 
 async fn dispatcher(
-    mut from_receiver: UnboundedReceiver<WithSender<ClientToServer>>,
-    online_users: Arc<DashMap<UserId, ClientRecord>>,
-    user_service: Arc<dyn UserService>,
+    mut from_receiver: Receiver<WithSender<ClientToServer>>,
+    state: Arc<ChatState>,
 ) {
     while let Some(message) = from_receiver.recv().await {
-        if let Err(e) = dispatch(online_users.clone(), user_service.clone(), message).await {
-            warn!("Error dispatching message: {}", e);
+        let sender = message.sender.clone();
+        let connection_id = message.connection_id;
+        let correlation_id = message.body.correlation_id().map(str::to_string);
+        if let Err(e) = dispatch(&state, message).await {
+            match e {
+                ChatError::InternalError(_) => warn!("Error dispatching message: {}", e),
+                _ => debug!("Rejected message from {:?}: {}", sender, e),
+            }
+            let error = ServerToClient::Error(ErrorMessage::new(&e, correlation_id));
+            let _ = send_to_connection(&state, &sender, connection_id, &error).await;
         }
     }
 }
 
-async fn dispatch(
-    online_users: Arc<DashMap<UserId, ClientRecord>>,
-    user_service: Arc<dyn UserService>,
-    message: WithSender<ClientToServer>,
-) -> Result<()> {
+async fn dispatch(state: &Arc<ChatState>, message: WithSender<ClientToServer>) -> Result<(), ChatError> {
     let sender = message.sender;
-    let content = match message.body {
-        ClientToServer::Send(message) => message.content,
-        _ => return Ok(()),
+    let connection_id = message.connection_id;
+    // Receipts count too: checking whether a watermark moves already takes the store's log.
+    check_rate_limit(state, &sender)?;
+    match message.body {
+        ClientToServer::Delete(request) => delete_message(state, sender, request).await,
+        ClientToServer::Send(message) => distribute(state, sender, connection_id, message).await,
+        ClientToServer::Edit(request) => edit_message(state, sender, request).await,
+        ClientToServer::HistoryFetched(request) => send_history(state, sender, connection_id, request).await,
+        ClientToServer::Mute(update) => update_mute(state, sender, update).await,
+        ClientToServer::Presence(update) => update_presence(state, sender, update).await,
+        ClientToServer::React(request) => react(state, sender, request).await,
+        ClientToServer::Read(update) => update_receipt(state, sender, update).await,
+        ClientToServer::Typing(update) => update_typing(state, sender, update).await,
+    }
+}
+
+async fn distribute(
+    state: &Arc<ChatState>,
+    sender: UserId,
+    connection_id: ConnectionId,
+    message: SendMessage,
+) -> Result<(), ChatError> {
+    let mut content = message.content;
+    if content.content.len() > state.config.max_message_size {
+        return Err(ChatError::TooLarge(state.config.max_message_size));
+    }
+    if message.attachment_ids.len() > MAX_ATTACHMENTS {
+        return Err(ChatError::TooManyAttachments(MAX_ATTACHMENTS));
+    }
+    // A retry goes to the same conversation, and so to the same worker, which handles one message
+    // at a time. So it can't race the original here.
+    let dedupe_key = message.client_message_id.map(|client_message_id| (sender.clone(), client_message_id));
+    if let Some(recent) = dedupe_key.as_ref().and_then(|key| state.recent_sends.get(key))
+        && recent.expire_at > Instant::now()
+    {
+        let ack = ServerToClient::Ack(AckMessage {
+            correlation_id: message.correlation_id,
+            message_id: recent.message_id,
+            sent_at: recent.sent_at,
+        });
+        drop(recent);
+        return Ok(send_to_connection(state, &sender, connection_id, &ack).await?);
+    }
+
+    if !state.user_service.is_member(&sender, &content.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+    if let Some(reply_to) = content.reply_to {
+        let parent = state
+            .message_store
+            .get(&content.conversation_id, reply_to)
+            .await?
+            .ok_or(ChatError::MessageNotFound)?;
+        // Threads are flat: a reply to a reply goes to the same thread.
+        content.reply_to = Some(parent.reply_to.unwrap_or(parent.id));
+    }
+    let recipients = state.user_service.get_receiver(&sender, &content.conversation_id).await?;
+    let attachments = state
+        .attachment_service
+        .post(&sender, &content.conversation_id, &message.attachment_ids)
+        .await?;
+    let attachments: Vec<_> = attachments.iter().map(AttachmentInfo::from).collect();
+    let stored = match state.message_store.append(&sender, &content, attachments.clone()).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            // So the sender can try again with the same attachments.
+            state.attachment_service.unpost(&message.attachment_ids).await;
+            return Err(e.into());
+        }
     };
-    let recipients = user_service.get_receiver(&sender, &content.conversation_id).await?;
+    let recipients = unmuted_recipients(state, &content, recipients).await?;
+    let was_typing = state.typing.remove(&(content.conversation_id.clone(), sender.clone())).is_some();
+    if let Some(key) = dedupe_key {
+        state.recent_sends.insert(key, RecentSend {
+            message_id: stored.id,
+            sent_at: stored.sent_at,
+            expire_at: Instant::now() + Duration::from_secs(state.config.dedupe_window),
+        });
+    }
+
+    let ack = ServerToClient::Ack(AckMessage {
+        correlation_id: message.correlation_id,
+        message_id: stored.id,
+        sent_at: stored.sent_at,
+    });
+    // The message is stored either way, so a closed sending connection doesn't stop the fan-out.
+    if let Err(e) = send_to_connection(state, &sender, connection_id, &ack).await {
+        debug!("Failed to ack message {:?}: {}", stored.id, e);
+    }
 
+    // Sending ends typing, announced first so the message doesn't show up under a typing indicator.
+    if was_typing && let Err(e) = broadcast_typing(state, &content.conversation_id, &sender, false).await {
+        warn!("Failed to announce the end of typing: {}", e);
+    }
+    // Rendering can take a while, so the thumbnails follow in an update rather than holding up the worker.
+    let pending_thumbnails = attachments
+        .iter()
+        .any(|attachment| attachment.content_type.starts_with("image/") && attachment.thumbnails.is_empty())
+        .then(|| attachments.clone());
+    let distributed = (content.conversation_id.clone(), stored.id);
     let distribute_message = ServerToClient::Distribute(DistributeMessage {
-        sender,
+        id: stored.id,
+        sender: sender.clone(),
         content,
+        attachments,
+        sent_at: stored.sent_at,
+    });
+    // The sending connection already has the message.
+    fan_out(state, &sender, &recipients, to_message(&distribute_message)?, Some(connection_id), Some(distributed.clone())).await;
+
+    if let Some(posted) = pending_thumbnails {
+        let (conversation_id, message_id) = distributed;
+        tokio::spawn(announce_thumbnails(state.clone(), sender, recipients, conversation_id, message_id, posted));
+    }
+    Ok(())
+}
+
+/// Add the thumbnails of a sent message's attachments once they are rendered, and tell everyone who got it.
+async fn announce_thumbnails(
+    state: Arc<ChatState>,
+    sender: UserId,
+    recipients: Vec<UserId>,
+    conversation_id: ConversationId,
+    message_id: MessageId,
+    posted: Vec<AttachmentInfo>,
+) {
+    if let Err(e) = update_thumbnails(&state, &sender, &recipients, conversation_id, message_id, posted).await {
+        warn!("Failed to announce the thumbnails of message {:?}: {}", message_id, e);
+    }
+}
+
+async fn update_thumbnails(
+    state: &ChatState,
+    sender: &UserId,
+    recipients: &[UserId],
+    conversation_id: ConversationId,
+    message_id: MessageId,
+    posted: Vec<AttachmentInfo>,
+) -> Result<()> {
+    let ids: Vec<_> = posted.iter().map(|attachment| attachment.id).collect();
+    let attachments = match state.attachment_service.wait_for_thumbnails(&ids).await {
+        Ok(attachments) => attachments,
+        // Deleted with the message meanwhile.
+        Err(AttachmentError::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let attachments: Vec<_> = attachments.iter().map(AttachmentInfo::from).collect();
+    let unchanged = attachments
+        .iter()
+        .zip(&posted)
+        .all(|(attachment, posted)| attachment.thumbnails.len() == posted.thumbnails.len());
+    if unchanged {
+        return Ok(());
+    }
+    let updated = state
+        .message_store
+        .update_attachments(&conversation_id, message_id, attachments.clone())
+        .await?;
+    if updated.is_none() {
+        return Ok(());
+    }
+
+    let attachment_update = ServerToClient::AttachmentUpdate(AttachmentUpdateEvent {
+        conversation_id,
+        message_id,
+        attachments,
+    });
+    fan_out(state, sender, recipients, to_message(&attachment_update)?, None, None).await;
+    Ok(())
+}
+
+async fn edit_message(state: &ChatState, user_id: UserId, request: EditMessage) -> Result<(), ChatError> {
+    if request.content.len() > state.config.max_message_size {
+        return Err(ChatError::TooLarge(state.config.max_message_size));
+    }
+    authorize_change(state, &user_id, &request.conversation_id, request.message_id).await?;
+    let recipients = state.user_service.get_receiver(&user_id, &request.conversation_id).await?;
+    let edited = state
+        .message_store
+        .edit(&request.conversation_id, request.message_id, &request.content)
+        .await?;
+
+    let edited_event = ServerToClient::Edited(EditedEvent {
+        conversation_id: request.conversation_id,
+        message_id: edited.id,
+        content: edited.content,
+        edited_at: edited.edited_at.ok_or(anyhow!("Edited message {:?} has no edit time", edited.id))?,
+        edited_by: user_id.clone(),
+    });
+    fan_out(state, &user_id, &recipients, to_message(&edited_event)?, None, None).await;
+    Ok(())
+}
+
+async fn delete_message(state: &ChatState, user_id: UserId, request: DeleteMessage) -> Result<(), ChatError> {
+    let message = authorize_change(state, &user_id, &request.conversation_id, request.message_id).await?;
+    let recipients = state.user_service.get_receiver(&user_id, &request.conversation_id).await?;
+    let deleted = state.message_store.delete(&request.conversation_id, request.message_id).await?;
+    // The tombstone no longer links them, so nobody may download them anymore.
+    let attachment_ids: Vec<_> = message.attachments.iter().map(|attachment| attachment.id).collect();
+    if let Err(e) = state.attachment_service.delete(&attachment_ids).await {
+        warn!("Failed to delete the attachments of message {:?}: {}", deleted.id, e);
+    }
+
+    let deleted_event = ServerToClient::Deleted(DeletedEvent {
+        conversation_id: request.conversation_id,
+        message_id: deleted.id,
+        deleted_at: deleted.deleted_at.ok_or(anyhow!("Deleted message {:?} has no delete time", deleted.id))?,
+        deleted_by: user_id.clone(),
+    });
+    fan_out(state, &user_id, &recipients, to_message(&deleted_event)?, None, None).await;
+    Ok(())
+}
+
+async fn react(state: &ChatState, user_id: UserId, request: ReactMessage) -> Result<(), ChatError> {
+    if request.emoji.len() > MAX_EMOJI_SIZE || !is_emoji(&request.emoji) {
+        return Err(ChatError::InvalidReaction(MAX_EMOJI_SIZE));
+    }
+    if !state.user_service.is_member(&user_id, &request.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+    let message = state
+        .message_store
+        .get(&request.conversation_id, request.message_id)
+        .await?
+        .filter(|message| message.deleted_at.is_none())
+        .ok_or(ChatError::MessageNotFound)?;
+    // The conversation's dispatcher handles one request at a time, so the count can't change before `react`.
+    let add = request.action == ReactionAction::Add;
+    if add && !message.reactions.contains_key(&request.emoji) && message.reactions.len() >= MAX_REACTIONS {
+        return Err(ChatError::TooManyReactions(MAX_REACTIONS));
+    }
+
+    let recipients = state.user_service.get_receiver(&user_id, &request.conversation_id).await?;
+    let reacted = state
+        .message_store
+        .react(&request.conversation_id, request.message_id, &user_id, &request.emoji, add)
+        .await?;
+    let Some(reacted) = reacted else {
+        return Ok(());
+    };
+
+    let reaction_update = ServerToClient::ReactionUpdate(ReactionUpdateEvent {
+        conversation_id: request.conversation_id,
+        message_id: reacted.id,
+        user_id: user_id.clone(),
+        count: reacted.reactions.get(&request.emoji).map_or(0, Vec::len),
+        emoji: request.emoji,
+        action: request.action,
     });
-    let message = serde_json::to_string(&distribute_message)?;
-    for recipient in recipients {
-        let client_record = online_users.get(&recipient).ok_or(anyhow!("User not online: {:?}", recipient))?;
-        client_record.to_sender.send(Message::text(message.clone()))?;
+    fan_out(state, &user_id, &recipients, to_message(&reaction_update)?, None, None).await;
+    Ok(())
+}
+
+/// Whether the reaction is a single emoji: one grapheme of pictographs and flag letters, with the joiners,
+/// variation selectors, skin tones and tags that combine them, or a keycap.
+fn is_emoji(reaction: &str) -> bool {
+    let mut graphemes = reaction.graphemes(true);
+    if graphemes.next().is_none() || graphemes.next().is_some() {
+        return false;
+    }
+    let mut chars = reaction.chars();
+    match chars.next() {
+        Some('#' | '*' | '0'..='9') => matches!(chars.as_str(), "\u{20e3}" | "\u{fe0f}\u{20e3}"),
+        Some(first) => {
+            is_pictograph(first)
+                && chars.all(|c| is_pictograph(c) || matches!(c, '\u{200d}' | '\u{fe0e}' | '\u{fe0f}' | '\u{e0020}'..='\u{e007f}'))
+        }
+        None => false,
+    }
+}
+
+/// Code points that display as emoji, including regional indicators and skin tones.
+fn is_pictograph(c: char) -> bool {
+    matches!(
+        c,
+        '\u{a9}'
+            | '\u{ae}'
+            | '\u{203c}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{2199}'
+            | '\u{21a9}'..='\u{21aa}'
+            | '\u{231a}'..='\u{231b}'
+            | '\u{2328}'
+            | '\u{23cf}'
+            | '\u{23e9}'..='\u{23f3}'
+            | '\u{23f8}'..='\u{23fa}'
+            | '\u{24c2}'
+            | '\u{25aa}'..='\u{25ab}'
+            | '\u{25b6}'
+            | '\u{25c0}'
+            | '\u{25fb}'..='\u{25fe}'
+            | '\u{2600}'..='\u{27bf}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2b05}'..='\u{2b07}'
+            | '\u{2b1b}'..='\u{2b1c}'
+            | '\u{2b50}'
+            | '\u{2b55}'
+            | '\u{3030}'
+            | '\u{303d}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1f000}'..='\u{1faff}'
+    )
+}
+
+/// Leave out the members who muted the conversation, unless the message is a reply in a thread they take part in.
+async fn unmuted_recipients(state: &ChatState, content: &ChatContent, recipients: Vec<UserId>) -> Result<Vec<UserId>> {
+    let muted = state.message_store.muted(&content.conversation_id).await?;
+    if muted.is_empty() {
+        return Ok(recipients);
     }
+    let participants = match content.reply_to {
+        Some(root) => state.message_store.thread_participants(&content.conversation_id, root).await?,
+        None => vec![],
+    };
+    Ok(recipients
+        .into_iter()
+        .filter(|recipient| !muted.contains(recipient) || participants.contains(recipient))
+        .collect())
+}
+
+async fn update_mute(state: &ChatState, user_id: UserId, update: MuteUpdate) -> Result<(), ChatError> {
+    if !state.user_service.is_member(&user_id, &update.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+    state.message_store.set_muted(&update.conversation_id, &user_id, update.muted).await?;
+    Ok(())
+}
+
+/// Only the sender or a conversation admin may change a message that still exists.
+async fn authorize_change(
+    state: &ChatState,
+    user_id: &UserId,
+    conversation_id: &ConversationId,
+    message_id: MessageId,
+) -> Result<StoredMessage, ChatError> {
+    if !state.user_service.is_member(user_id, conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+    let message = state
+        .message_store
+        .get(conversation_id, message_id)
+        .await?
+        .filter(|message| message.deleted_at.is_none())
+        .ok_or(ChatError::MessageNotFound)?;
+    if message.sender != *user_id && !state.user_service.is_admin(user_id, conversation_id).await? {
+        return Err(ChatError::Forbidden);
+    }
+    Ok(message)
+}
+
+/// Deliver to the recipients and keep the actor's devices in sync, except the connection that acted.
+/// `distributed` is the conversation and id of a chat message, whose delivery is then recorded.
+async fn fan_out(
+    state: &ChatState,
+    actor: &UserId,
+    recipients: &[UserId],
+    message: Message,
+    except: Option<ConnectionId>,
+    distributed: Option<(ConversationId, MessageId)>,
+) {
+    for recipient in recipients.iter().filter(|recipient| *recipient != actor) {
+        deliver(state, recipient, message.clone(), distributed.clone()).await;
+    }
+    for outbox in outboxes(state, actor, except) {
+        let _ = outbox.push(message.clone()).await;
+    }
+}
+
+/// Send to every device of the user if they are online, otherwise queue for their next `join_chat`.
+async fn deliver(state: &ChatState, user_id: &UserId, message: Message, distributed: Option<(ConversationId, MessageId)>) {
+    let queued = QueuedMessage { frame: message, distributed };
+    let outboxes = {
+        let mut queue = state.offline_queues.entry(user_id.clone()).or_default();
+        let outboxes = outboxes(state, user_id, None);
+        if outboxes.is_empty() {
+            queue_offline(state, user_id, &mut queue, queued);
+            return;
+        }
+        outboxes
+    };
+
+    // Pushing may wait under `OverflowPolicy::Backpressure`, so no map entry is held from here on.
+    let mut delivered = false;
+    for outbox in outboxes {
+        // A closed outbox means the connection is going away.
+        delivered |= outbox.push(queued.clone()).await.is_ok();
+    }
+    if !delivered {
+        let mut queue = state.offline_queues.entry(user_id.clone()).or_default();
+        queue_offline(state, user_id, &mut queue, queued);
+    }
+}
+
+fn queue_offline(state: &ChatState, user_id: &UserId, queue: &mut VecDeque<QueuedMessage>, message: QueuedMessage) {
+    if queue.len() >= state.config.offline_queue_capacity {
+        queue.pop_front();
+        state.dropped_messages.fetch_add(1, Ordering::Relaxed);
+        debug!("Offline queue of {:?} is full, dropped the oldest message", user_id);
+    }
+    queue.push_back(message);
+}
+
+/// Send to every device of the user if they are online, otherwise drop the message.
+async fn notify(state: &ChatState, user_id: &UserId, message: &Message) {
+    for outbox in outboxes(state, user_id, None) {
+        let _ = outbox.push(message.clone()).await;
+    }
+}
+
+async fn update_presence(state: &ChatState, user_id: UserId, update: PresenceUpdate) -> Result<(), ChatError> {
+    let status = if update.away { PresenceStatus::Away } else { PresenceStatus::Online };
+    let changed = match state.presence.get_mut(&user_id) {
+        Some(mut current) if *current != status => {
+            *current = status;
+            true
+        }
+        // Unchanged, or the user went offline meanwhile.
+        _ => false,
+    };
+    if changed {
+        broadcast_presence(state, &user_id, status).await?;
+    }
+    Ok(())
+}
+
+async fn broadcast_presence(state: &ChatState, user_id: &UserId, status: PresenceStatus) -> Result<()> {
+    let message = to_message(&ServerToClient::Presence(PresenceEvent {
+        user_id: user_id.clone(),
+        status,
+    }))?;
+    for contact in state.user_service.get_contacts(user_id).await? {
+        notify(state, &contact, &message).await;
+    }
+    Ok(())
+}
+
+async fn update_typing(state: &ChatState, user_id: UserId, update: TypingUpdate) -> Result<(), ChatError> {
+    if !state.user_service.is_member(&user_id, &update.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+
+    let key = (update.conversation_id.clone(), user_id.clone());
+    // Only starting and stopping are announced, repeats just push the expiry back.
+    let changed = if update.typing {
+        let expire_at = Instant::now() + Duration::from_secs(state.config.typing_timeout);
+        state.typing.insert(key, expire_at).is_none()
+    } else {
+        state.typing.remove(&key).is_some()
+    };
+    if changed {
+        broadcast_typing(state, &update.conversation_id, &user_id, update.typing).await?;
+    }
+    Ok(())
+}
+
+async fn broadcast_typing(
+    state: &ChatState,
+    conversation_id: &ConversationId,
+    user_id: &UserId,
+    typing: bool,
+) -> Result<()> {
+    let message = to_message(&ServerToClient::Typing(TypingEvent {
+        conversation_id: conversation_id.clone(),
+        user_id: user_id.clone(),
+        typing,
+    }))?;
+    let recipients = state.user_service.get_receiver(user_id, conversation_id).await?;
+    for recipient in recipients.iter().filter(|recipient| *recipient != user_id) {
+        notify(state, recipient, &message).await;
+    }
+    Ok(())
+}
+
+/// The outboxes of the user's open connections, optionally leaving one out.
+fn outboxes(state: &ChatState, user_id: &UserId, except: Option<ConnectionId>) -> Vec<Arc<Outbox>> {
+    state.online_users.get(user_id).map_or_else(Vec::new, |connections| {
+        connections
+            .iter()
+            .filter(|(connection_id, _)| Some(**connection_id) != except)
+            .map(|(_, client_record)| client_record.outbox.clone())
+            .collect()
+    })
+}
+
+async fn send_history(
+    state: &ChatState,
+    user_id: UserId,
+    connection_id: ConnectionId,
+    request: FetchHistory,
+) -> Result<(), ChatError> {
+    if !state.user_service.is_member(&user_id, &request.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+
+    let page_size = state.config.history_page_size.max(1);
+    let query = HistoryQuery {
+        conversation_id: request.conversation_id,
+        before: request.before,
+        limit: request.limit.unwrap_or(page_size).clamp(1, page_size),
+        thread: request.thread,
+    };
+    let page = state.message_store.history(&query).await?;
+    let receipts = state.message_store.receipts(&query.conversation_id).await?;
+
+    let history = ServerToClient::History(HistoryPage {
+        conversation_id: query.conversation_id,
+        thread: query.thread,
+        messages: page.messages.into_iter().map(|message| HistoryMessage::new(message, &user_id)).collect(),
+        has_more: page.has_more,
+        receipts: receipts.into_iter().map(ReceiptEvent::from).collect(),
+    });
+    Ok(send_to_connection(state, &user_id, connection_id, &history).await?)
+}
+
+async fn update_receipt(state: &ChatState, user_id: UserId, update: ReceiptUpdate) -> Result<(), ChatError> {
+    if !state.user_service.is_member(&user_id, &update.conversation_id).await? {
+        return Err(ChatError::NotAMember);
+    }
+    record_receipt(state, &user_id, &update.conversation_id, ReceiptKind::Read, update.up_to_message_id).await?;
+    Ok(())
+}
+
+/// Move the member's watermark and tell the other members if it moved.
+async fn record_receipt(
+    state: &ChatState,
+    user_id: &UserId,
+    conversation_id: &ConversationId,
+    kind: ReceiptKind,
+    up_to: MessageId,
+) -> Result<()> {
+    let receipt = state.message_store.update_receipt(conversation_id, user_id, kind, up_to).await?;
+    let Some(receipt) = receipt else {
+        return Ok(());
+    };
+
+    // Offline members catch up through the receipts in the history.
+    let message = to_message(&ServerToClient::Receipt(ReceiptEvent::from(receipt)))?;
+    let recipients = state.user_service.get_receiver(user_id, conversation_id).await?;
+    for recipient in recipients.iter().filter(|recipient| *recipient != user_id) {
+        notify(state, recipient, &message).await;
+    }
+    Ok(())
+}
+
+/// Count a message against the user's rate limit window, starting a new window if the last one is over.
+fn check_rate_limit(state: &ChatState, user_id: &UserId) -> Result<(), ChatError> {
+    let now = Instant::now();
+    let window = Duration::from_secs(state.config.rate_limit_window);
+    let mut rate_window = state.rate_windows.entry(user_id.clone()).or_insert(RateWindow {
+        started_at: now,
+        count: 0,
+    });
+    if now.duration_since(rate_window.started_at) >= window {
+        rate_window.started_at = now;
+        rate_window.count = 0;
+    }
+    if rate_window.count >= state.config.rate_limit_messages {
+        return Err(ChatError::RateLimited);
+    }
+    rate_window.count += 1;
+    Ok(())
+}
+
+fn to_message(message: &ServerToClient) -> Result<Message> {
+    Ok(Message::text(serde_json::to_string(message)?))
+}
+
+/// Reply to the one connection a request came from.
+async fn send_to_connection(
+    state: &ChatState,
+    user_id: &UserId,
+    connection_id: ConnectionId,
+    message: &ServerToClient,
+) -> Result<()> {
+    let outbox = state
+        .online_users
+        .get(user_id)
+        .and_then(|connections| connections.get(&connection_id).map(|client_record| client_record.outbox.clone()))
+        .ok_or(anyhow!("Connection closed: {:?}", connection_id))?;
+    outbox.push(to_message(message)?).await.map_err(|_| anyhow!("Connection closed: {:?}", connection_id))?;
     Ok(())
 }
 
 impl FakeChatService {
-    pub fn new(user_service: Arc<dyn UserService>) -> Self {
-        let (to_dispatcher, from_receiver) = unbounded_channel();
-        let online_users = Arc::new(DashMap::new());
-        let dispatcher_handle = tokio::spawn(dispatcher(
-            from_receiver,
-            online_users.clone(),
-            user_service.clone(),
-        ));
+    pub fn new(
+        config: ChatConfig,
+        user_service: Arc<dyn UserService>,
+        message_store: Arc<dyn MessageStore>,
+        attachment_service: Arc<dyn AttachmentService>,
+    ) -> Self {
+        let worker_count = config.dispatcher_workers.max(1);
+        let capacity = config.dispatcher_capacity.max(1);
+        let state = Arc::new(ChatState {
+            config,
+            online_users: DashMap::new(),
+            offline_queues: DashMap::new(),
+            recent_sends: DashMap::new(),
+            rate_windows: DashMap::new(),
+            presence: DashMap::new(),
+            typing: DashMap::new(),
+            dropped_messages: AtomicU64::new(0),
+            user_service,
+            message_store,
+            attachment_service,
+        });
+        let (workers, dispatcher_handles): (Vec<_>, Vec<_>) = (0..worker_count)
+            .map(|_| {
+                let (to_dispatcher, from_receiver) = channel(capacity);
+                (to_dispatcher, tokio::spawn(dispatcher(from_receiver, state.clone())))
+            })
+            .unzip();
+        let sweeper_handle = tokio::spawn(sweeper(state.clone()));
+        let typing_handle = tokio::spawn(typing_sweeper(state.clone()));
 
         Self {
-            user_service,
-            online_users,
-            to_dispatcher,
-            dispatcher_handle,
+            state,
+            dispatchers: Dispatchers {
+                workers: workers.into(),
+            },
+            dispatcher_handles,
+            sweeper_handle,
+            typing_handle,
+        }
+    }
+}
+
+impl Drop for FakeChatService {
+    fn drop(&mut self) {
+        for dispatcher_handle in &self.dispatcher_handles {
+            dispatcher_handle.abort();
         }
+        self.sweeper_handle.abort();
+        self.typing_handle.abort();
+    }
+}
+
+/// Announce the end of typing nobody repeated within the typing timeout.
+async fn typing_sweeper(state: Arc<ChatState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        state.typing.retain(|key, expire_at| {
+            if *expire_at > now {
+                return true;
+            }
+            expired.push(key.clone());
+            false
+        });
+        for (conversation_id, user_id) in expired {
+            if let Err(e) = broadcast_typing(&state, &conversation_id, &user_id, false).await {
+                warn!("Failed to announce the end of typing: {}", e);
+            }
+        }
+    }
+}
+
+async fn sweeper(state: Arc<ChatState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.dedupe_window.max(1)));
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let rate_limit_window = Duration::from_secs(state.config.rate_limit_window);
+        state.recent_sends.retain(|_, recent| recent.expire_at > now);
+        state.rate_windows.retain(|_, rate_window| now.duration_since(rate_window.started_at) < rate_limit_window);
     }
 }
 
@@ -98,101 +840,258 @@ impl FakeChatService {
 impl ChatService for FakeChatService {
     async fn join_chat(
         &self,
-        mut to_user: SplitSink<WebSocket, Message>,
-        mut from_user: SplitStream<WebSocket>,
+        to_user: SplitSink<WebSocket, Message>,
+        from_user: SplitStream<WebSocket>,
         user_id: UserId,
     ) -> Result<(), anyhow::Error> {
-        let (to_sender, from_dispatcher) = unbounded_channel();
-        let sender_handle = tokio::spawn(sender(from_dispatcher, to_user));
+        let connection_id = ConnectionId(Uuid::new_v4());
+        let config = &self.state.config;
+        let outbox = Arc::new(Outbox::new(config.outbox_capacity, config.overflow_policy));
+        let sender_handle = tokio::spawn(sender(outbox.clone(), to_user, user_id.clone(), self.state.clone()));
+        let liveness = Arc::new(Liveness::new());
         let receiver_handle = tokio::spawn(receiver(
             from_user,
             user_id.clone(),
-            to_sender.clone(),
-            self.to_dispatcher.clone(),
+            connection_id,
+            outbox.clone(),
+            liveness.clone(),
+            self.dispatchers.clone(),
         ));
+        let heartbeat_handle = tokio::spawn(heartbeat(outbox.clone(), liveness, self.state.clone()));
         let watcher_handle = tokio::spawn(watcher(
             sender_handle,
             receiver_handle,
+            heartbeat_handle,
             user_id.clone(),
-            self.online_users.clone(),
+            connection_id,
+            outbox.clone(),
+            self.state.clone(),
         ));
 
+        // Flush queued messages while holding the queue, so `deliver` can't queue
+        // a message behind our back after we went online.
+        // The offline queue is bounded on its own, so it may exceed the outbox capacity.
+        let mut queue = self.state.offline_queues.entry(user_id.clone()).or_default();
+        outbox.extend(queue.drain(..));
+
         let user_id_clone = user_id.clone();
         let new_user = ClientRecord {
-            user_id,
-            to_sender,
+            user_id: user_id.clone(),
+            outbox: outbox.clone(),
             watcher_handle,
         };
-        self.online_users.insert(user_id_clone, new_user);
-        debug!("online_users: {}", self.online_users.len());
+        let mut connections = self.state.online_users.entry(user_id_clone).or_default();
+        let came_online = connections.is_empty();
+        connections.insert(connection_id, new_user);
+        if came_online {
+            self.state.presence.insert(user_id.clone(), PresenceStatus::Online);
+        }
+        drop(connections);
+        drop(queue);
+        debug!("online_users: {}", self.state.online_users.len());
+
+        if came_online {
+            broadcast_presence(&self.state, &user_id, PresenceStatus::Online).await?;
+        }
+        // Let the new connection know who is around.
+        for contact in self.state.user_service.get_contacts(&user_id).await? {
+            let Some(status) = self.state.presence.get(&contact).map(|status| *status) else {
+                continue;
+            };
+            let presence = ServerToClient::Presence(PresenceEvent {
+                user_id: contact,
+                status,
+            });
+            let _ = outbox.push(to_message(&presence)?).await;
+        }
 
         Ok(())
     }
+
+    async fn disconnect_user(&self, user_id: &UserId) -> Result<(), anyhow::Error> {
+        // Nothing queued is written anymore. The watcher removes the record once the sender has flushed the close frame.
+        if let Some(connections) = self.state.online_users.get(user_id) {
+            for client_record in connections.values() {
+                client_record.outbox.close_now(Message::close_with(CLOSE_SESSION_REVOKED, "Session revoked"));
+            }
+        }
+        Ok(())
+    }
 }
 
 // region join_chat helpers
 
+/// Write queued frames to the socket, recording the delivery of chat messages once they are written.
 async fn sender(
-    mut from_dispatcher: UnboundedReceiver<Message>,
+    outbox: Arc<Outbox>,
     mut to_user: SplitSink<WebSocket, Message>,
+    user_id: UserId,
+    state: Arc<ChatState>,
 ) {
-    while let Some(message) = from_dispatcher.recv().await {
-        if let Err(_) = to_user.send(message).await {
+    while let Some(queued) = outbox.recv().await {
+        let is_close = queued.frame.is_close();
+        if to_user.send(queued.frame).await.is_err() || is_close {
             break;
         }
+        if let Some((conversation_id, message_id)) = queued.distributed
+            && let Err(e) = record_receipt(&state, &user_id, &conversation_id, ReceiptKind::Delivered, message_id).await
+        {
+            warn!("Failed to record delivery of {:?} to {:?}: {}", message_id, user_id, e);
+        }
     }
 }
 
 async fn receiver(
     mut from_user: SplitStream<WebSocket>,
     user_id: UserId,
-    to_sender: UnboundedSender<Message>,
-    to_dispatcher: UnboundedSender<WithSender<ClientToServer>>,
+    connection_id: ConnectionId,
+    outbox: Arc<Outbox>,
+    liveness: Arc<Liveness>,
+    dispatchers: Dispatchers,
 ) {
     while let Some(result) = from_user.next().await {
         let message = match result {
             Ok(message) => message,
             Err(_) => break,
         };
+        liveness.record(&message);
 
-        if let Err(e) = handle_recv_message(&to_sender, &user_id, &to_dispatcher, &message).await {
-            warn!("Failed to receive message: {}", e);
+        if let Err(e) = handle_recv_message(&user_id, connection_id, &dispatchers, &message).await {
+            debug!("Rejected frame from {:?}: {}", user_id, e);
+            let correlation_id = message.to_str().ok().and_then(find_correlation_id);
+            let error = ServerToClient::Error(ErrorMessage::new(&e, correlation_id));
+            if let Ok(error) = to_message(&error) {
+                let _ = outbox.push(error).await;
+            }
         }
     }
 }
 
 async fn handle_recv_message(
-    to_sender: &UnboundedSender<Message>,
     user_id: &UserId,
-    to_dispatcher: &UnboundedSender<WithSender<ClientToServer>>,
+    connection_id: ConnectionId,
+    dispatchers: &Dispatchers,
     message: &Message,
-) -> Result<()> {
-    if message.is_ping() {
-        let _ = to_sender.send(Message::pong(vec![]));
-        Ok(())
-    } else if message.is_text() {
+) -> Result<(), ChatError> {
+    if message.is_text() {
         let text = message.to_str().unwrap_or_default();
-        let body = serde_json::from_str::<ClientToServer>(&text)?;
+        let body = serde_json::from_str::<ClientToServer>(text).map_err(ChatError::ParseError)?;
         let protocol_message = WithSender {
             sender: user_id.clone(),
+            connection_id,
             body,
         };
-        let _ = to_dispatcher.send(protocol_message);
+        // Waits while the worker is backed up, which stops reading from this client meanwhile.
+        let _ = dispatchers.send(protocol_message).await;
         Ok(())
+    } else if message.is_binary() {
+        Err(ChatError::UnsupportedFrame)
     } else {
-        Err(anyhow!("Unexpected message type"))
+        // Pings, which the websocket answers by itself, pongs, and close frames which end the stream right after.
+        Ok(())
+    }
+}
+
+/// Best effort lookup of `payload.correlation_id` in a frame that didn't parse as a `ClientToServer`.
+fn find_correlation_id(text: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(text).ok()?;
+    Some(value.get("payload")?.get("correlation_id")?.as_str()?.to_string())
+}
+
+/// What the receiver has heard from the client, checked by the heartbeat.
+struct Liveness {
+    missed_pongs: AtomicU32,
+    last_message_at: Mutex<Instant>,
+}
+
+impl Liveness {
+    fn new() -> Self {
+        Self {
+            missed_pongs: AtomicU32::new(0),
+            last_message_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn record(&self, message: &Message) {
+        if message.is_pong() {
+            self.missed_pongs.store(0, Ordering::Relaxed);
+        } else if message.is_text() || message.is_binary() {
+            *self.last_message_at.lock().unwrap() = Instant::now();
+        }
     }
 }
 
+/// Ping the client every `ping_interval` and close the connection once it is dead or idle.
+/// Returns one interval after asking to close, which makes the watcher tear the connection down
+/// if the close frame couldn't be written, e.g. on a half-open TCP connection.
+async fn heartbeat(outbox: Arc<Outbox>, liveness: Arc<Liveness>, state: Arc<ChatState>) {
+    let ping_interval = Duration::from_secs(state.config.ping_interval.max(1));
+    let idle_timeout = Duration::from_secs(state.config.idle_timeout);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    loop {
+        interval.tick().await;
+        let idle_for = liveness.last_message_at.lock().unwrap().elapsed();
+        if !idle_timeout.is_zero() && idle_for >= idle_timeout {
+            outbox.close(Message::close_with(CLOSE_IDLE_TIMEOUT, "Idle timeout"));
+            break;
+        }
+        if liveness.missed_pongs.fetch_add(1, Ordering::Relaxed) >= state.config.max_missed_pongs {
+            outbox.close(Message::close_with(CLOSE_HEARTBEAT_TIMEOUT, "Heartbeat timeout"));
+            break;
+        }
+        // Never waits on a full outbox, so the checks above keep running for a stalled client.
+        let _ = outbox.push_control(Message::ping(vec![]));
+    }
+    interval.tick().await;
+}
+
 async fn watcher(
-    sender_handle: JoinHandle<()>,
-    receiver_handle: JoinHandle<()>,
+    mut sender_handle: JoinHandle<()>,
+    mut receiver_handle: JoinHandle<()>,
+    mut heartbeat_handle: JoinHandle<()>,
     user_id: UserId,
-    online_users: Arc<DashMap<UserId, ClientRecord>>,
+    connection_id: ConnectionId,
+    outbox: Arc<Outbox>,
+    state: Arc<ChatState>,
 ) -> Result<()> {
-    let result = tokio::try_join!(sender_handle, receiver_handle);
-    online_users.remove(&user_id);
-    debug!("online_users: {}", online_users.len());
+    // Any task finishing ends the connection: a closed socket ends the receiver, a failed write
+    // or a server-initiated close ends the sender, and a close frame stuck in the outbox ends the heartbeat.
+    let result = tokio::select! {
+        result = &mut sender_handle => result,
+        result = &mut receiver_handle => result,
+        result = &mut heartbeat_handle => result,
+    };
+    sender_handle.abort();
+    receiver_handle.abort();
+    heartbeat_handle.abort();
+    // Wake up anyone waiting for room in the outbox, nothing will drain it anymore.
+    outbox.shutdown();
+    if outbox.dropped() > 0 {
+        debug!("Connection {:?} of {:?} dropped {} messages", connection_id, user_id, outbox.dropped());
+        state.dropped_messages.fetch_add(outbox.dropped(), Ordering::Relaxed);
+    }
+    // Only this connection is gone, the user may still be online on other devices.
+    if let Some(mut connections) = state.online_users.get_mut(&user_id) {
+        connections.remove(&connection_id);
+    }
+    let went_offline = state
+        .online_users
+        .remove_if(&user_id, |_, connections| {
+            if !connections.is_empty() {
+                return false;
+            }
+            state.presence.remove(&user_id);
+            true
+        })
+        .is_some();
+    debug!("online_users: {}", state.online_users.len());
+
+    if went_offline {
+        state.typing.retain(|(_, typing_user), _| *typing_user != user_id);
+        if let Err(e) = broadcast_presence(&state, &user_id, PresenceStatus::Offline).await {
+            warn!("Failed to announce {:?} going offline: {}", user_id, e);
+        }
+    }
     result.map_or_else(|e| Err(anyhow!(e)), |_| Ok(()))
 }
 
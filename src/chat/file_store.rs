@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use crate::attachment::AttachmentInfo;
+use crate::chat::*;
+use crate::domain::{ConversationId, MessageId, UserId};
+
+/// One line of the log. Records are written whole, so replaying is just restoring each one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+enum StoreEvent {
+    Message(StoredMessage),
+    Receipt(Receipt),
+    Mute(MuteSetting),
+}
+
+/// Serves reads from memory and appends every change to a JSON lines log,
+/// which is replayed on startup. A change that can't be logged is undone in memory too,
+/// so callers never see state that would be lost on restart. Replaying compacts the log if records were replaced,
+/// so deleted content doesn't linger on disk past a restart.
+#[derive(Debug)]
+pub struct FileMessageStore {
+    inner: MemoryMessageStore,
+    log: Mutex<File>,
+}
+
+impl FileMessageStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let inner = MemoryMessageStore::new();
+        let mut replayed = 0;
+        match std::fs::read_to_string(&path) {
+            Ok(log) => {
+                for (index, line) in log.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+                    let event = serde_json::from_str::<StoreEvent>(line)
+                        .map_err(|e| anyhow!("Corrupt message log {:?} at line {}: {}", path, index + 1, e))?;
+                    match event {
+                        StoreEvent::Message(message) => inner.restore(message),
+                        StoreEvent::Receipt(receipt) => inner.restore_receipt(receipt),
+                        StoreEvent::Mute(setting) => inner.restore_mute(setting),
+                    }
+                    replayed += 1;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Failed to read message log {:?}: {}", path, e)),
+        }
+
+        let (messages, receipts, mutes) = inner.snapshot();
+        if replayed > messages.len() + receipts.len() + mutes.len() {
+            Self::compact(&path, messages, receipts, mutes)?;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            inner,
+            log: Mutex::new(File::from_std(log)),
+        })
+    }
+
+    /// Rewrite the log with one line per record, replacing the file atomically.
+    fn compact(path: &Path, messages: Vec<StoredMessage>, receipts: Vec<Receipt>, mutes: Vec<MuteSetting>) -> Result<()> {
+        let mut log = Vec::new();
+        let events = messages
+            .into_iter()
+            .map(StoreEvent::Message)
+            .chain(receipts.into_iter().map(StoreEvent::Receipt))
+            .chain(mutes.into_iter().map(StoreEvent::Mute));
+        for event in events {
+            serde_json::to_writer(&mut log, &event)?;
+            log.push(b'\n');
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, log)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    async fn write(log: &mut File, event: &StoreEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let len = log.metadata().await?.len();
+        let written = async {
+            log.write_all(&line).await?;
+            log.flush().await
+        };
+        if let Err(e) = written.await {
+            // Cut off a partly written line, so it can't corrupt the records after it.
+            let _ = log.set_len(len).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Log an updated message, putting back the version before the update if that fails.
+    async fn write_or_restore(&self, log: &mut File, message: &StoredMessage, before: Option<StoredMessage>) -> Result<()> {
+        let result = Self::write(log, &StoreEvent::Message(message.clone())).await;
+        if result.is_err()
+            && let Some(before) = before
+        {
+            self.inner.restore(before);
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageStore for FileMessageStore {
+    async fn append(
+        &self,
+        sender: &UserId,
+        content: &ChatContent,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<StoredMessage> {
+        // Holding the log across the update keeps the log in the same order as memory.
+        let mut log = self.log.lock().await;
+        let message = self.inner.next_message(sender, content, attachments);
+        Self::write(&mut log, &StoreEvent::Message(message.clone())).await?;
+        self.inner.restore(message.clone());
+        Ok(message)
+    }
+
+    async fn get(&self, conversation_id: &ConversationId, id: MessageId) -> Result<Option<StoredMessage>> {
+        self.inner.get(conversation_id, id).await
+    }
+
+    async fn edit(&self, conversation_id: &ConversationId, id: MessageId, content: &str) -> Result<StoredMessage> {
+        let mut log = self.log.lock().await;
+        let before = self.inner.get(conversation_id, id).await?;
+        let message = self.inner.edit(conversation_id, id, content).await?;
+        self.write_or_restore(&mut log, &message, before).await?;
+        Ok(message)
+    }
+
+    async fn delete(&self, conversation_id: &ConversationId, id: MessageId) -> Result<StoredMessage> {
+        let mut log = self.log.lock().await;
+        let before = self.inner.get(conversation_id, id).await?;
+        let message = self.inner.delete(conversation_id, id).await?;
+        self.write_or_restore(&mut log, &message, before).await?;
+        Ok(message)
+    }
+
+    async fn update_attachments(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<Option<StoredMessage>> {
+        let mut log = self.log.lock().await;
+        let before = self.inner.get(conversation_id, id).await?;
+        let message = self.inner.update_attachments(conversation_id, id, attachments).await?;
+        if let Some(message) = &message {
+            self.write_or_restore(&mut log, message, before).await?;
+        }
+        Ok(message)
+    }
+
+    async fn react(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        user_id: &UserId,
+        emoji: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>> {
+        let mut log = self.log.lock().await;
+        let before = self.inner.get(conversation_id, id).await?;
+        let message = self.inner.react(conversation_id, id, user_id, emoji, add).await?;
+        if let Some(message) = &message {
+            self.write_or_restore(&mut log, message, before).await?;
+        }
+        Ok(message)
+    }
+
+    async fn history(&self, query: &HistoryQuery) -> Result<StoredPage> {
+        self.inner.history(query).await
+    }
+
+    async fn thread_participants(&self, conversation_id: &ConversationId, root: MessageId) -> Result<Vec<UserId>> {
+        self.inner.thread_participants(conversation_id, root).await
+    }
+
+    async fn set_muted(&self, conversation_id: &ConversationId, user_id: &UserId, muted: bool) -> Result<bool> {
+        let mut log = self.log.lock().await;
+        let changed = self.inner.set_muted(conversation_id, user_id, muted).await?;
+        if changed {
+            let setting = MuteSetting {
+                conversation_id: conversation_id.clone(),
+                user_id: user_id.clone(),
+                muted,
+            };
+            if let Err(e) = Self::write(&mut log, &StoreEvent::Mute(setting)).await {
+                self.inner.restore_mute(MuteSetting {
+                    conversation_id: conversation_id.clone(),
+                    user_id: user_id.clone(),
+                    muted: !muted,
+                });
+                return Err(e);
+            }
+        }
+        Ok(changed)
+    }
+
+    async fn muted(&self, conversation_id: &ConversationId) -> Result<Vec<UserId>> {
+        self.inner.muted(conversation_id).await
+    }
+
+    async fn update_receipt(
+        &self,
+        conversation_id: &ConversationId,
+        user_id: &UserId,
+        kind: ReceiptKind,
+        up_to: MessageId,
+    ) -> Result<Option<Receipt>> {
+        let mut log = self.log.lock().await;
+        let before = self.inner.receipt(conversation_id, user_id);
+        let receipt = self.inner.update_receipt(conversation_id, user_id, kind, up_to).await?;
+        if let Some(receipt) = &receipt
+            && let Err(e) = Self::write(&mut log, &StoreEvent::Receipt(receipt.clone())).await
+        {
+            match before {
+                Some(before) => self.inner.restore_receipt(before),
+                None => self.inner.remove_receipt(conversation_id, user_id),
+            }
+            return Err(e);
+        }
+        Ok(receipt)
+    }
+
+    async fn receipts(&self, conversation_id: &ConversationId) -> Result<Vec<Receipt>> {
+        self.inner.receipts(conversation_id).await
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use dashmap::DashMap;
+use crate::attachment::AttachmentInfo;
+use crate::chat::*;
+use crate::domain::{ConversationId, MessageId, UserId};
+
+#[derive(Debug)]
+pub struct MemoryMessageStore {
+    next_id: AtomicU64,
+    /// Each conversation's messages, sorted by id.
+    conversations: DashMap<ConversationId, Vec<StoredMessage>>,
+    receipts: DashMap<ConversationId, HashMap<UserId, Receipt>>,
+    /// Participants of each thread, by conversation and root.
+    threads: DashMap<(ConversationId, MessageId), Vec<UserId>>,
+    muted: DashMap<ConversationId, HashSet<UserId>>,
+}
+
+impl MemoryMessageStore {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            conversations: DashMap::new(),
+            receipts: DashMap::new(),
+            threads: DashMap::new(),
+            muted: DashMap::new(),
+        }
+    }
+
+    /// Insert or replace a message with its existing id, e.g. when replaying a log.
+    pub fn restore(&self, message: StoredMessage) {
+        self.next_id.fetch_max(message.id.0 + 1, Ordering::SeqCst);
+        let mut messages = self.conversations.entry(message.conversation_id.clone()).or_default();
+        self.join_thread(&messages, &message);
+        match messages.binary_search_by_key(&message.id, |stored| stored.id) {
+            Ok(index) => messages[index] = message,
+            Err(index) => messages.insert(index, message),
+        }
+    }
+
+    /// Build a message with the next id without storing it, so a log can record it first.
+    pub fn next_message(&self, sender: &UserId, content: &ChatContent, attachments: Vec<AttachmentInfo>) -> StoredMessage {
+        StoredMessage {
+            id: MessageId(self.next_id.fetch_add(1, Ordering::SeqCst)),
+            sender: sender.clone(),
+            conversation_id: content.conversation_id.clone(),
+            content: content.content.clone(),
+            sent_at: Utc::now(),
+            reply_to: content.reply_to,
+            attachments,
+            edits: vec![],
+            edited_at: None,
+            deleted_at: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    /// Every message, receipt and mute, e.g. to compact a log.
+    pub fn snapshot(&self) -> (Vec<StoredMessage>, Vec<Receipt>, Vec<MuteSetting>) {
+        let mut messages: Vec<_> = self.conversations.iter().flat_map(|entry| entry.value().clone()).collect();
+        messages.sort_by_key(|message| message.id);
+        let receipts = self.receipts.iter().flat_map(|entry| entry.value().values().cloned().collect::<Vec<_>>()).collect();
+        let mutes = self
+            .muted
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .map(|user_id| MuteSetting {
+                        conversation_id: entry.key().clone(),
+                        user_id: user_id.clone(),
+                        muted: true,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        (messages, receipts, mutes)
+    }
+
+    /// Add a reply's sender, and the root's sender before them, to the thread's participants.
+    fn join_thread(&self, messages: &[StoredMessage], message: &StoredMessage) {
+        let Some(root) = message.reply_to else {
+            return;
+        };
+        let mut participants = self.threads.entry((message.conversation_id.clone(), root)).or_default();
+        if participants.is_empty()
+            && let Ok(index) = messages.binary_search_by_key(&root, |stored| stored.id)
+        {
+            participants.push(messages[index].sender.clone());
+        }
+        if !participants.contains(&message.sender) {
+            participants.push(message.sender.clone());
+        }
+    }
+
+    fn update(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        update: impl FnOnce(&mut StoredMessage),
+    ) -> Result<StoredMessage> {
+        let mut messages = self
+            .conversations
+            .get_mut(conversation_id)
+            .ok_or(anyhow!("Conversation not found: {:?}", conversation_id))?;
+        let index = messages
+            .binary_search_by_key(&id, |stored| stored.id)
+            .map_err(|_| anyhow!("Message not found: {:?}", id))?;
+        update(&mut messages[index]);
+        Ok(messages[index].clone())
+    }
+
+    /// Insert or replace a member's receipt, e.g. when replaying a log.
+    pub fn restore_receipt(&self, receipt: Receipt) {
+        let mut receipts = self.receipts.entry(receipt.conversation_id.clone()).or_default();
+        receipts.insert(receipt.user_id.clone(), receipt);
+    }
+
+    /// A member's receipt, e.g. to undo an update that couldn't be logged.
+    pub fn receipt(&self, conversation_id: &ConversationId, user_id: &UserId) -> Option<Receipt> {
+        self.receipts.get(conversation_id).and_then(|receipts| receipts.get(user_id).cloned())
+    }
+
+    /// Remove a member's receipt, e.g. to undo an update that couldn't be logged.
+    pub fn remove_receipt(&self, conversation_id: &ConversationId, user_id: &UserId) {
+        if let Some(mut receipts) = self.receipts.get_mut(conversation_id) {
+            receipts.remove(user_id);
+        }
+    }
+
+    /// Apply a mute setting, e.g. when replaying a log.
+    pub fn restore_mute(&self, setting: MuteSetting) {
+        let mut muted = self.muted.entry(setting.conversation_id).or_default();
+        if setting.muted {
+            muted.insert(setting.user_id);
+        } else {
+            muted.remove(&setting.user_id);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageStore for MemoryMessageStore {
+    async fn append(
+        &self,
+        sender: &UserId,
+        content: &ChatContent,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<StoredMessage> {
+        // The id is taken while holding the conversation's entry, so messages stay sorted.
+        let mut messages = self.conversations.entry(content.conversation_id.clone()).or_default();
+        let message = self.next_message(sender, content, attachments);
+        self.join_thread(&messages, &message);
+        messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn get(&self, conversation_id: &ConversationId, id: MessageId) -> Result<Option<StoredMessage>> {
+        let Some(messages) = self.conversations.get(conversation_id) else {
+            return Ok(None);
+        };
+        Ok(messages
+            .binary_search_by_key(&id, |stored| stored.id)
+            .ok()
+            .map(|index| messages[index].clone()))
+    }
+
+    async fn edit(&self, conversation_id: &ConversationId, id: MessageId, content: &str) -> Result<StoredMessage> {
+        self.update(conversation_id, id, |message| {
+            let now = Utc::now();
+            let previous = std::mem::replace(&mut message.content, content.to_string());
+            message.edits.push(PreviousVersion {
+                content: previous,
+                replaced_at: now,
+            });
+            message.edited_at = Some(now);
+        })
+    }
+
+    async fn delete(&self, conversation_id: &ConversationId, id: MessageId) -> Result<StoredMessage> {
+        self.update(conversation_id, id, |message| {
+            message.content.clear();
+            message.attachments.clear();
+            message.edits.clear();
+            message.reactions.clear();
+            message.deleted_at = Some(Utc::now());
+        })
+    }
+
+    async fn update_attachments(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<Option<StoredMessage>> {
+        let mut changed = false;
+        let message = self.update(conversation_id, id, |message| {
+            if message.deleted_at.is_none() {
+                message.attachments = attachments;
+                changed = true;
+            }
+        })?;
+        Ok(changed.then_some(message))
+    }
+
+    async fn react(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        user_id: &UserId,
+        emoji: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>> {
+        let mut changed = false;
+        let message = self.update(conversation_id, id, |message| {
+            if message.deleted_at.is_some() {
+                return;
+            }
+            let users = message.reactions.entry(emoji.to_string()).or_default();
+            let position = users.iter().position(|user| user == user_id);
+            match (add, position) {
+                (true, None) => {
+                    users.push(user_id.clone());
+                    changed = true;
+                }
+                (false, Some(position)) => {
+                    users.remove(position);
+                    changed = true;
+                }
+                _ => {}
+            }
+            if users.is_empty() {
+                message.reactions.remove(emoji);
+            }
+        })?;
+        Ok(changed.then_some(message))
+    }
+
+    async fn history(&self, query: &HistoryQuery) -> Result<StoredPage> {
+        let Some(messages) = self.conversations.get(&query.conversation_id) else {
+            return Ok(StoredPage { messages: vec![], has_more: false });
+        };
+        let end = match query.before {
+            Some(before) => messages.partition_point(|message| message.id < before),
+            None => messages.len(),
+        };
+        // Replies are kept in line with the main timeline, so both are picked out by their root.
+        let mut matching = messages[..end].iter().rev().filter(|message| message.reply_to == query.thread);
+        let mut page: Vec<_> = matching.by_ref().take(query.limit).cloned().collect();
+        let has_more = matching.next().is_some();
+        page.reverse();
+        Ok(StoredPage { messages: page, has_more })
+    }
+
+    async fn thread_participants(&self, conversation_id: &ConversationId, root: MessageId) -> Result<Vec<UserId>> {
+        Ok(self
+            .threads
+            .get(&(conversation_id.clone(), root))
+            .map(|participants| participants.clone())
+            .unwrap_or_default())
+    }
+
+    async fn set_muted(&self, conversation_id: &ConversationId, user_id: &UserId, muted: bool) -> Result<bool> {
+        let mut muted_users = self.muted.entry(conversation_id.clone()).or_default();
+        Ok(if muted {
+            muted_users.insert(user_id.clone())
+        } else {
+            muted_users.remove(user_id)
+        })
+    }
+
+    async fn muted(&self, conversation_id: &ConversationId) -> Result<Vec<UserId>> {
+        Ok(self
+            .muted
+            .get(conversation_id)
+            .map(|muted_users| muted_users.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn update_receipt(
+        &self,
+        conversation_id: &ConversationId,
+        user_id: &UserId,
+        kind: ReceiptKind,
+        up_to: MessageId,
+    ) -> Result<Option<Receipt>> {
+        let newest = self
+            .conversations
+            .get(conversation_id)
+            .and_then(|messages| messages.last().map(|message| message.id));
+        let Some(newest) = newest else {
+            return Ok(None);
+        };
+        let up_to = up_to.min(newest);
+
+        let mut receipts = self.receipts.entry(conversation_id.clone()).or_default();
+        let receipt = receipts.entry(user_id.clone()).or_insert_with(|| Receipt {
+            conversation_id: conversation_id.clone(),
+            user_id: user_id.clone(),
+            delivered_up_to: None,
+            read_up_to: None,
+        });
+        let before = receipt.clone();
+        receipt.delivered_up_to = receipt.delivered_up_to.max(Some(up_to));
+        if kind == ReceiptKind::Read {
+            receipt.read_up_to = receipt.read_up_to.max(Some(up_to));
+        }
+        Ok((*receipt != before).then(|| receipt.clone()))
+    }
+
+    async fn receipts(&self, conversation_id: &ConversationId) -> Result<Vec<Receipt>> {
+        Ok(self
+            .receipts
+            .get(conversation_id)
+            .map(|receipts| receipts.values().cloned().collect())
+            .unwrap_or_default())
+    }
+}
@@ -1,15 +1,153 @@
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
-use crate::domain::{ConversationId, UserId};
+use crate::attachment::AttachmentInfo;
+use crate::chat::{ChatError, Receipt, StoredMessage};
+use crate::domain::{AttachmentId, ConversationId, MessageId, UserId};
+
+/// WebSocket close code sent when the user's session was revoked by a logout.
+pub const CLOSE_SESSION_REVOKED: u16 = 4001;
+/// WebSocket close code sent when the connection's outbox overflowed under `OverflowPolicy::Disconnect`.
+pub const CLOSE_SLOW_CONSUMER: u16 = 4002;
+/// WebSocket close code sent when the client stopped answering the server's pings.
+pub const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4003;
+/// WebSocket close code sent when the client sent no messages for the idle timeout.
+pub const CLOSE_IDLE_TIMEOUT: u16 = 4004;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "lowercase")]
 pub enum ClientToServer {
-    HistoryFetched,
+    Delete(DeleteMessage),
+    Edit(EditMessage),
+    HistoryFetched(FetchHistory),
+    Mute(MuteUpdate),
+    Presence(PresenceUpdate),
+    React(ReactMessage),
+    Read(ReceiptUpdate),
     Send(SendMessage),
+    Typing(TypingUpdate),
+}
+
+impl ClientToServer {
+    /// `None` for messages that aren't about one conversation.
+    pub fn conversation_id(&self) -> Option<&ConversationId> {
+        match self {
+            ClientToServer::Delete(request) => Some(&request.conversation_id),
+            ClientToServer::Read(update) => Some(&update.conversation_id),
+            ClientToServer::Edit(request) => Some(&request.conversation_id),
+            ClientToServer::HistoryFetched(request) => Some(&request.conversation_id),
+            ClientToServer::Mute(update) => Some(&update.conversation_id),
+            ClientToServer::Presence(_) => None,
+            ClientToServer::React(request) => Some(&request.conversation_id),
+            ClientToServer::Send(message) => Some(&message.content.conversation_id),
+            ClientToServer::Typing(update) => Some(&update.conversation_id),
+        }
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        match self {
+            ClientToServer::Delete(request) => request.correlation_id.as_deref(),
+            ClientToServer::Read(update) => update.correlation_id.as_deref(),
+            ClientToServer::Edit(request) => request.correlation_id.as_deref(),
+            ClientToServer::HistoryFetched(request) => request.correlation_id.as_deref(),
+            ClientToServer::Mute(update) => update.correlation_id.as_deref(),
+            ClientToServer::Presence(update) => update.correlation_id.as_deref(),
+            ClientToServer::React(request) => request.correlation_id.as_deref(),
+            ClientToServer::Send(message) => message.correlation_id.as_deref(),
+            ClientToServer::Typing(update) => update.correlation_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchHistory {
+    /// Chosen by the client and echoed back in an `Error`.
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    /// Cursor: fetch messages older than this id. Omit to start at the newest message.
+    pub before: Option<MessageId>,
+    /// Capped by the server's page size, which is also the default.
+    pub limit: Option<usize>,
+    /// Fetch the replies to this thread root instead of the main timeline.
+    pub thread: Option<MessageId>,
+}
+
+/// Only the sender or a conversation admin may edit a message, and not once it is deleted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditMessage {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub content: String,
+}
+
+/// Only the sender or a conversation admin may delete a message. It stays in the history as a tombstone.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteMessage {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+}
+
+/// Adding a reaction twice or removing one that isn't there changes nothing.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactMessage {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub emoji: String,
+    pub action: ReactionAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReactionAction {
+    Add,
+    Remove,
+}
+
+/// Clients send `Read` once the user saw the messages, marking everything up to the id.
+/// Delivery is recorded by the server as messages are written to the user's connections.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiptUpdate {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub up_to_message_id: MessageId,
+}
+
+/// A muted conversation's new messages are no longer delivered to the user,
+/// except replies in threads they take part in. They stay in the history either way.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MuteUpdate {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub muted: bool,
+}
+
+/// Online users are `online` unless they say they are away.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresenceUpdate {
+    pub correlation_id: Option<String>,
+    pub away: bool,
+}
+
+/// Send `typing: true` again every few seconds while the user keeps typing, the server forgets
+/// it after its typing timeout. A sent message ends typing as well.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypingUpdate {
+    pub correlation_id: Option<String>,
+    pub conversation_id: ConversationId,
+    pub typing: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SendMessage {
+    /// Chosen by the client and echoed back in the `Ack` or `Error`.
+    pub correlation_id: Option<String>,
+    /// Idempotency key: a retry with the same id gets the original `Ack` and is not distributed again.
+    pub client_message_id: Option<String>,
+    /// Uploaded through `/api/v1/attachments`, posting them makes them available to the conversation.
+    #[serde(default)]
+    pub attachment_ids: Vec<AttachmentId>,
     #[serde(flatten)]
     pub content: ChatContent,
 }
@@ -17,18 +155,224 @@ pub struct SendMessage {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "lowercase")]
 pub enum ServerToClient {
+    Ack(AckMessage),
+    AttachmentUpdate(AttachmentUpdateEvent),
+    Deleted(DeletedEvent),
     Distribute(DistributeMessage),
+    Edited(EditedEvent),
+    Error(ErrorMessage),
+    History(HistoryPage),
+    Presence(PresenceEvent),
+    ReactionUpdate(ReactionUpdateEvent),
+    Receipt(ReceiptEvent),
+    Typing(TypingEvent),
+}
+
+/// Sent to the connection a message came from once it is stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckMessage {
+    pub correlation_id: Option<String>,
+    pub message_id: MessageId,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Sent to the connection whose message was malformed or rejected.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+    /// `None` if the offending message had none or could not be parsed far enough to find it.
+    pub correlation_id: Option<String>,
+}
+
+impl ErrorMessage {
+    pub fn new(error: &ChatError, correlation_id: Option<String>) -> Self {
+        let message = match error {
+            // Details stay in the server log.
+            ChatError::InternalError(_) => "Internal error".to_string(),
+            error => error.to_string(),
+        };
+        Self {
+            code: error.code(),
+            message,
+            correlation_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ParseError,
+    UnsupportedFrame,
+    NotAMember,
+    NotFound,
+    Forbidden,
+    InvalidReaction,
+    InvalidAttachment,
+    RateLimited,
+    TooLarge,
+    InternalError,
+}
+
+/// Sent to the members of the conversation, including every device of the editor.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditedEvent {
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub content: String,
+    pub edited_at: DateTime<Utc>,
+    pub edited_by: UserId,
+}
+
+/// Sent to the members of the conversation, including every device of the deleter.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletedEvent {
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub deleted_at: DateTime<Utc>,
+    pub deleted_by: UserId,
+}
+
+/// Sent to the recipients of a message, and every device of its sender, once the thumbnails
+/// of its attachments are rendered. Carries all of the message's attachments.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttachmentUpdateEvent {
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+/// Sent to the members of the conversation, including every device of the reacting user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionUpdateEvent {
+    pub conversation_id: ConversationId,
+    pub message_id: MessageId,
+    pub user_id: UserId,
+    pub emoji: String,
+    pub action: ReactionAction,
+    /// How many users reacted with `emoji` now.
+    pub count: usize,
+}
+
+/// Sent to the other members of the conversation when a member's watermarks move.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReceiptEvent {
+    pub conversation_id: ConversationId,
+    pub user_id: UserId,
+    pub delivered_up_to: Option<MessageId>,
+    pub read_up_to: Option<MessageId>,
+}
+
+impl From<Receipt> for ReceiptEvent {
+    fn from(receipt: Receipt) -> Self {
+        Self {
+            conversation_id: receipt.conversation_id,
+            user_id: receipt.user_id,
+            delivered_up_to: receipt.delivered_up_to,
+            read_up_to: receipt.read_up_to,
+        }
+    }
+}
+
+/// Sent to a user's contacts when their status changes, and for each online contact when a user connects.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresenceEvent {
+    pub user_id: UserId,
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+/// Sent to the other members of the conversation, `typing: false` also when typing timed out.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub conversation_id: ConversationId,
+    pub user_id: UserId,
+    pub typing: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DistributeMessage {
+    pub id: MessageId,
     pub sender: UserId,
     #[serde(flatten)]
     pub content: ChatContent,
+    pub attachments: Vec<AttachmentInfo>,
+    pub sent_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatContent {
     pub conversation_id: ConversationId,
     pub content: String,
+    /// The thread root this message replies to. Replying to a reply joins the same thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub conversation_id: ConversationId,
+    /// The thread root whose replies these are, `None` for the main timeline.
+    pub thread: Option<MessageId>,
+    /// Oldest first. Pass the first id as `before` to fetch the previous page.
+    pub messages: Vec<HistoryMessage>,
+    pub has_more: bool,
+    /// Everyone's current watermarks in the conversation.
+    pub receipts: Vec<ReceiptEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    pub id: MessageId,
+    pub sender: UserId,
+    /// Empty for deleted messages.
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub reply_to: Option<MessageId>,
+    pub attachments: Vec<AttachmentInfo>,
+    pub reactions: Vec<ReactionSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: usize,
+    /// Whether the user fetching the history reacted with this emoji.
+    pub me: bool,
+}
+
+impl HistoryMessage {
+    /// The message as seen by `viewer`.
+    pub fn new(message: StoredMessage, viewer: &UserId) -> Self {
+        let reactions = message
+            .reactions
+            .into_iter()
+            .map(|(emoji, users)| ReactionSummary {
+                emoji,
+                count: users.len(),
+                me: users.contains(viewer),
+            })
+            .collect();
+        Self {
+            id: message.id,
+            sender: message.sender,
+            content: message.content,
+            sent_at: message.sent_at,
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            reply_to: message.reply_to,
+            attachments: message.attachments,
+            reactions,
+        }
+    }
 }
@@ -1,7 +1,15 @@
 mod chat;
 mod fake_chat;
+mod file_store;
+mod memory_store;
 mod message;
+mod outbox;
+mod store;
 
 pub use chat::*;
 pub use fake_chat::*;
-pub use message::*;
\ No newline at end of file
+pub use file_store::*;
+pub use memory_store::*;
+pub use message::*;
+pub use outbox::*;
+pub use store::*;
\ No newline at end of file
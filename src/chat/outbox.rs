@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use warp::ws::Message;
+use crate::chat::CLOSE_SLOW_CONSUMER;
+use crate::domain::{ConversationId, MessageId};
+
+/// What an `Outbox` does with a new message when it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Drop everything queued and close the connection with `CLOSE_SLOW_CONSUMER`.
+    Disconnect,
+    /// Make the pushing task wait until the connection catches up,
+    /// then disconnect like `Disconnect` if it hasn't within the timeout.
+    Backpressure(Duration),
+}
+
+/// A frame waiting to be written. Chat messages keep their conversation and id,
+/// so their delivery is recorded once they are written to the recipient's connection.
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub frame: Message,
+    pub distributed: Option<(ConversationId, MessageId)>,
+}
+
+impl From<Message> for QueuedMessage {
+    fn from(frame: Message) -> Self {
+        Self { frame, distributed: None }
+    }
+}
+
+/// The push side is closed, the message was not queued.
+#[derive(Debug)]
+pub struct OutboxClosed(pub Message);
+
+impl Debug for Outbox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+/// Bounded queue of frames waiting to be written to one connection.
+/// Any number of tasks push, the connection's sender task is the only one receiving.
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+    dropped: AtomicU64,
+}
+
+struct OutboxState {
+    queue: VecDeque<QueuedMessage>,
+    closed: bool,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(OutboxState {
+                queue: VecDeque::new(),
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a message, applying the overflow policy if the outbox is full.
+    /// Only `OverflowPolicy::Backpressure` ever waits, and never longer than its timeout.
+    /// A message that disconnects the slow consumer isn't queued, and comes back as `OutboxClosed`.
+    pub async fn push(&self, message: impl Into<QueuedMessage>) -> Result<u64, OutboxClosed> {
+        let mut message = message.into();
+        let deadline = match self.policy {
+            OverflowPolicy::Backpressure(timeout) => Instant::now() + timeout,
+            OverflowPolicy::DropOldest | OverflowPolicy::Disconnect => Instant::now(),
+        };
+        loop {
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            match self.try_push(message) {
+                Err(TryPush::Full(returned)) => message = returned,
+                Err(TryPush::Closed(returned)) => return Err(OutboxClosed(returned.frame)),
+                Ok(dropped) => return Ok(dropped),
+            }
+            if tokio::time::timeout_at(deadline, writable).await.is_err() {
+                let mut state = self.state.lock().unwrap();
+                if !state.closed {
+                    self.disconnect(&mut state);
+                }
+                return Err(OutboxClosed(message.frame));
+            }
+        }
+    }
+
+    /// Queue a control frame, like a ping, regardless of capacity and without waiting.
+    pub fn push_control(&self, message: Message) -> Result<(), OutboxClosed> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(OutboxClosed(message));
+        }
+        state.queue.push_back(message.into());
+        drop(state);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queue messages regardless of capacity, e.g. the offline queue of a fresh connection.
+    pub fn extend(&self, messages: impl IntoIterator<Item = QueuedMessage>) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.extend(messages);
+        drop(state);
+        self.readable.notify_one();
+    }
+
+    /// Queue a close frame after everything already queued and refuse further pushes.
+    pub fn close(&self, frame: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.queue.push_back(frame.into());
+        state.closed = true;
+        drop(state);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    /// Drop whatever is queued in favour of a close frame and refuse further pushes,
+    /// for a connection that must not get anything else.
+    pub fn close_now(&self, frame: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        state.queue.clear();
+        state.queue.push_back(frame.into());
+        state.closed = true;
+        drop(state);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    /// Drop whatever is queued and refuse further pushes, once the connection is gone.
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.clear();
+        state.closed = true;
+        drop(state);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    /// The next frame to write, or `None` once the outbox is closed and drained.
+    pub async fn recv(&self) -> Option<QueuedMessage> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(message) = state.queue.pop_front() {
+                    drop(state);
+                    self.writable.notify_waiters();
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Messages dropped by the overflow policy so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many messages were dropped to queue this one.
+    fn try_push(&self, message: QueuedMessage) -> Result<u64, TryPush> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(TryPush::Closed(message));
+        }
+
+        let mut dropped = 0;
+        if state.queue.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    dropped = 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    self.disconnect(&mut state);
+                    return Err(TryPush::Closed(message));
+                }
+                OverflowPolicy::Backpressure(_) => return Err(TryPush::Full(message)),
+            }
+        }
+        state.queue.push_back(message);
+        drop(state);
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        self.readable.notify_one();
+        Ok(dropped)
+    }
+
+    /// Drop everything queued in favour of a `CLOSE_SLOW_CONSUMER` close frame.
+    fn disconnect(&self, state: &mut OutboxState) {
+        self.dropped.fetch_add(state.queue.len() as u64, Ordering::Relaxed);
+        state.queue.clear();
+        state.queue.push_back(Message::close_with(CLOSE_SLOW_CONSUMER, "Slow consumer").into());
+        state.closed = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+enum TryPush {
+    Full(QueuedMessage),
+    Closed(QueuedMessage),
+}
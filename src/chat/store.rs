@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::attachment::AttachmentInfo;
+use crate::chat::ChatContent;
+use crate::domain::{ConversationId, MessageId, UserId};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: MessageId,
+    pub sender: UserId,
+    pub conversation_id: ConversationId,
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+    /// The thread root, for replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentInfo>,
+    /// Earlier versions of the content, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<PreviousVersion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Set on tombstones, which keep neither content, attachments, edits nor reactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Who reacted with each emoji, in the order they did.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, Vec<UserId>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviousVersion {
+    pub content: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+/// How far a member got in a conversation. Watermarks only ever move forward.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub conversation_id: ConversationId,
+    pub user_id: UserId,
+    pub delivered_up_to: Option<MessageId>,
+    pub read_up_to: Option<MessageId>,
+}
+
+/// A member muted a conversation, or unmuted it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MuteSetting {
+    pub conversation_id: ConversationId,
+    pub user_id: UserId,
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+#[derive(Debug)]
+pub struct HistoryQuery {
+    pub conversation_id: ConversationId,
+    /// Only return messages older than this one. `None` starts at the newest message.
+    pub before: Option<MessageId>,
+    pub limit: usize,
+    /// Only return replies to this thread root. `None` is the main timeline, which leaves replies out.
+    pub thread: Option<MessageId>,
+}
+
+#[derive(Debug)]
+pub struct StoredPage {
+    /// Oldest first.
+    pub messages: Vec<StoredMessage>,
+    pub has_more: bool,
+}
+
+#[async_trait::async_trait]
+pub trait MessageStore: Debug + Send + Sync {
+    /// Store a message, assigning its id and timestamp. A reply's sender joins the thread.
+    async fn append(
+        &self,
+        sender: &UserId,
+        content: &ChatContent,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<StoredMessage>;
+
+    async fn get(&self, conversation_id: &ConversationId, id: MessageId) -> Result<Option<StoredMessage>>;
+
+    /// Replace the content, keeping the previous version in `edits`.
+    async fn edit(&self, conversation_id: &ConversationId, id: MessageId, content: &str) -> Result<StoredMessage>;
+
+    /// Turn the message into a tombstone.
+    async fn delete(&self, conversation_id: &ConversationId, id: MessageId) -> Result<StoredMessage>;
+
+    /// Replace the metadata of the message's attachments, e.g. once their thumbnails are rendered.
+    /// Returns the updated message, or `None` if it was deleted meanwhile.
+    async fn update_attachments(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        attachments: Vec<AttachmentInfo>,
+    ) -> Result<Option<StoredMessage>>;
+
+    /// Add or remove the user's reaction. Returns the updated message, or `None` if nothing changed.
+    async fn react(
+        &self,
+        conversation_id: &ConversationId,
+        id: MessageId,
+        user_id: &UserId,
+        emoji: &str,
+        add: bool,
+    ) -> Result<Option<StoredMessage>>;
+
+    /// Fetch the `limit` messages of the timeline or thread right before `query.before`.
+    async fn history(&self, query: &HistoryQuery) -> Result<StoredPage>;
+
+    /// The root's sender and everyone who replied, in the order they joined.
+    async fn thread_participants(&self, conversation_id: &ConversationId, root: MessageId) -> Result<Vec<UserId>>;
+
+    /// Returns whether the setting changed.
+    async fn set_muted(&self, conversation_id: &ConversationId, user_id: &UserId, muted: bool) -> Result<bool>;
+
+    /// The members who muted the conversation.
+    async fn muted(&self, conversation_id: &ConversationId) -> Result<Vec<UserId>>;
+
+    /// Move a member's watermark forward to `up_to`, capped at the newest message. Reading implies delivery.
+    /// Returns the updated receipt, or `None` if nothing moved.
+    async fn update_receipt(
+        &self,
+        conversation_id: &ConversationId,
+        user_id: &UserId,
+        kind: ReceiptKind,
+        up_to: MessageId,
+    ) -> Result<Option<Receipt>>;
+
+    /// The receipts of every member who got any message of the conversation.
+    async fn receipts(&self, conversation_id: &ConversationId) -> Result<Vec<Receipt>>;
+}
\ No newline at end of file
@@ -0,0 +1,5 @@
+use serde::{Deserialize, Serialize};
+
+/// Server-assigned when a file is uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AttachmentId(pub uuid::Uuid);
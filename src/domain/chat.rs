@@ -1,4 +1,12 @@
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct ConversationId(pub uuid::Uuid);
\ No newline at end of file
+pub struct ConversationId(pub uuid::Uuid);
+
+/// Server-assigned and increasing in the order messages are stored, so it doubles as a cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MessageId(pub u64);
+
+/// One WebSocket connection of a user, who may be connected from several devices at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub uuid::Uuid);
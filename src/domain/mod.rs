@@ -1,5 +1,7 @@
+mod attachment;
 mod chat;
 mod user;
 
+pub use attachment::*;
 pub use chat::*;
 pub use user::*;
\ No newline at end of file
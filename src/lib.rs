@@ -1,8 +1,11 @@
+#![allow(clippy::module_inception, clippy::new_without_default)]
+
 pub mod api;
 pub mod domain;
 pub mod logger;
 pub mod settings;
 
+pub mod attachment;
 pub mod auth;
 pub mod captcha;
 pub mod chat;
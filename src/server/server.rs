@@ -1,4 +1,6 @@
 use std::sync::Arc;
+use std::time::Duration;
+use crate::attachment::*;
 use crate::auth::*;
 use crate::captcha::*;
 use crate::chat::*;
@@ -7,6 +9,7 @@ use crate::user::*;
 use crate::settings::Settings;
 
 pub struct Server {
+    pub attachment_service: Arc<dyn AttachmentService>,
     pub auth_service: Arc<dyn AuthService>,
     pub captcha_service: Arc<dyn CaptchaService>,
     pub chat_service: Arc<dyn ChatService>,
@@ -15,14 +18,58 @@ pub struct Server {
 
 impl Server {
     pub fn try_new(settings: &Settings) -> anyhow::Result<Self> {
-        let captcha_service = match settings.captcha.backend.as_str() {
+        let captcha_config = CaptchaConfig {
+            ttl: settings.captcha.ttl,
+            length: settings.captcha.length,
+            width: settings.captcha.width,
+            height: settings.captcha.height,
+            complexity: settings.captcha.complexity,
+        };
+        let captcha_service: Arc<dyn CaptchaService> = match settings.captcha.backend.as_str() {
             "fake" => Arc::new(FakeCaptchaService::new()),
+            "memory" => Arc::new(MemoryCaptchaService::new(
+                captcha_config,
+                Duration::from_secs(settings.captcha.sweep_interval),
+            )),
+            "redis" => {
+                let redis_config = RedisConfig {
+                    url: settings.captcha.redis_url.clone(),
+                    key_prefix: settings.captcha.redis_key_prefix.clone(),
+                };
+                Arc::new(RedisCaptchaService::new(captcha_config, &redis_config)?)
+            }
             other => return Err(anyhow::anyhow!("Unknown captcha backend: {}", other)),
         };
         debug!(?captcha_service);
 
-        let auth_service = match settings.auth.backend.as_str() {
-            "fake" => Arc::new(FakeAuthService::new()),
+        let auth_service: Arc<dyn AuthService> = match settings.auth.backend.as_str() {
+            "fake" => Arc::new(FakeAuthService::new(Duration::from_secs(settings.auth.ws_ticket_ttl))),
+            "jwt" => {
+                let jwt_config = JwtConfig {
+                    secret: settings.auth.jwt_secret.clone(),
+                    issuer: settings.auth.jwt_issuer.clone(),
+                    audience: settings.auth.jwt_audience.clone(),
+                    access_token_ttl: settings.auth.access_token_ttl,
+                    refresh_token_ttl: settings.auth.refresh_token_ttl,
+                    ws_ticket_ttl: settings.auth.ws_ticket_ttl,
+                };
+                let password_config = PasswordConfig {
+                    memory_cost: settings.auth.argon2_memory_cost,
+                    time_cost: settings.auth.argon2_time_cost,
+                    parallelism: settings.auth.argon2_parallelism,
+                };
+                let credential_store: Arc<dyn CredentialStore> = match settings.auth.credential_store.as_str() {
+                    "memory" => Arc::new(MemoryCredentialStore::new()),
+                    "file" => Arc::new(FileCredentialStore::open(&settings.auth.credential_path)?),
+                    other => return Err(anyhow::anyhow!("Unknown credential store: {}", other)),
+                };
+                let family_store: Arc<dyn TokenFamilyStore> = match settings.auth.token_family_store.as_str() {
+                    "memory" => Arc::new(MemoryTokenFamilyStore::new()),
+                    "file" => Arc::new(FileTokenFamilyStore::open(&settings.auth.token_family_path)?),
+                    other => return Err(anyhow::anyhow!("Unknown token family store: {}", other)),
+                };
+                Arc::new(JwtAuthService::new(&jwt_config, &password_config, credential_store, family_store)?)
+            }
             other => return Err(anyhow::anyhow!("Unknown auth backend: {}", other)),
         };
         debug!(?auth_service);
@@ -33,13 +80,62 @@ impl Server {
         };
         debug!(?user_service);
 
+        let attachment_config = AttachmentConfig {
+            max_size: settings.attachment.max_size,
+            user_quota: settings.attachment.user_quota,
+            thumbnail_sizes: settings.attachment.thumbnail_sizes.clone(),
+        };
+        let attachment_service: Arc<dyn AttachmentService> = match settings.attachment.backend.as_str() {
+            "file" => Arc::new(FileAttachmentService::open(
+                attachment_config,
+                &settings.attachment.path,
+                user_service.clone(),
+            )?),
+            other => return Err(anyhow::anyhow!("Unknown attachment backend: {}", other)),
+        };
+        debug!(?attachment_service);
+
+        let message_store: Arc<dyn MessageStore> = match settings.chat.store.as_str() {
+            "memory" => Arc::new(MemoryMessageStore::new()),
+            "file" => Arc::new(FileMessageStore::open(&settings.chat.store_path)?),
+            other => return Err(anyhow::anyhow!("Unknown chat store: {}", other)),
+        };
+        debug!(?message_store);
+
+        let chat_config = ChatConfig {
+            history_page_size: settings.chat.history_page_size,
+            offline_queue_capacity: settings.chat.offline_queue_capacity,
+            dedupe_window: settings.chat.dedupe_window,
+            max_message_size: settings.chat.max_message_size,
+            rate_limit_messages: settings.chat.rate_limit_messages,
+            rate_limit_window: settings.chat.rate_limit_window,
+            dispatcher_workers: settings.chat.dispatcher_workers,
+            dispatcher_capacity: settings.chat.dispatcher_capacity,
+            outbox_capacity: settings.chat.outbox_capacity,
+            overflow_policy: match settings.chat.overflow_policy.as_str() {
+                "drop_oldest" => OverflowPolicy::DropOldest,
+                "disconnect" => OverflowPolicy::Disconnect,
+                "backpressure" => OverflowPolicy::Backpressure(Duration::from_millis(settings.chat.backpressure_timeout)),
+                other => return Err(anyhow::anyhow!("Unknown chat overflow policy: {}", other)),
+            },
+            ping_interval: settings.chat.ping_interval,
+            max_missed_pongs: settings.chat.max_missed_pongs,
+            idle_timeout: settings.chat.idle_timeout,
+            typing_timeout: settings.chat.typing_timeout,
+        };
         let chat_service = match settings.chat.backend.as_str() {
-            "fake" => Arc::new(FakeChatService::new(user_service.clone())),
+            "fake" => Arc::new(FakeChatService::new(
+                chat_config,
+                user_service.clone(),
+                message_store,
+                attachment_service.clone(),
+            )),
             other => return Err(anyhow::anyhow!("Unknown chat backend: {}", other)),
         };
         debug!(?chat_service);
 
         Ok(Self{
+            attachment_service,
             auth_service,
             captcha_service,
             chat_service,
@@ -1,9 +1,11 @@
 use anyhow::{Result, anyhow};
 use config::{Config, File};
 use serde::Deserialize;
+use std::fmt::{self, Debug, Formatter};
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    pub attachment: Attachment,
     pub auth: Auth,
     pub captcha: Captcha,
     pub chat: Chat,
@@ -13,18 +15,102 @@ pub struct Settings {
 }
 
 #[derive(Debug, Deserialize)]
+pub struct Attachment {
+    pub backend: String,  // "file"
+    pub path: String,
+    pub max_size: u64,  // bytes
+    pub user_quota: u64,  // bytes
+    pub thumbnail_sizes: Vec<u32>,  // pixels, longest edge
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("backend", &self.backend)
+            .field("jwt_secret", &"<redacted>")
+            .field("jwt_issuer", &self.jwt_issuer)
+            .field("jwt_audience", &self.jwt_audience)
+            .field("access_token_ttl", &self.access_token_ttl)
+            .field("refresh_token_ttl", &self.refresh_token_ttl)
+            .field("ws_ticket_ttl", &self.ws_ticket_ttl)
+            .field("credential_store", &self.credential_store)
+            .field("credential_path", &self.credential_path)
+            .field("token_family_store", &self.token_family_store)
+            .field("token_family_path", &self.token_family_path)
+            .field("argon2_memory_cost", &self.argon2_memory_cost)
+            .field("argon2_time_cost", &self.argon2_time_cost)
+            .field("argon2_parallelism", &self.argon2_parallelism)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
 pub struct Auth {
-    pub backend: String,  // "fake" or "real"
+    pub backend: String,  // "fake" or "jwt"
+    pub jwt_secret: String,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub access_token_ttl: u64,  // seconds
+    pub refresh_token_ttl: u64,  // seconds
+    pub ws_ticket_ttl: u64,  // seconds
+    pub credential_store: String,  // "memory" or "file"
+    pub credential_path: String,
+    pub token_family_store: String,  // "memory" or "file"
+    pub token_family_path: String,
+    pub argon2_memory_cost: u32,  // KiB
+    pub argon2_time_cost: u32,
+    pub argon2_parallelism: u32,
 }
 
-#[derive(Debug, Deserialize)]
+impl Debug for Captcha {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Captcha")
+            .field("backend", &self.backend)
+            .field("ttl", &self.ttl)
+            .field("length", &self.length)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("complexity", &self.complexity)
+            .field("sweep_interval", &self.sweep_interval)
+            .field("redis_url", &"<redacted>")
+            .field("redis_key_prefix", &self.redis_key_prefix)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
 pub struct Captcha {
-    pub backend: String,  // "fake" or "redis"
+    pub backend: String,  // "fake", "memory" or "redis"
+    pub ttl: u64,  // seconds
+    pub length: usize,
+    pub width: u32,
+    pub height: u32,
+    pub complexity: u32,  // 1 to 10
+    pub sweep_interval: u64,  // seconds
+    pub redis_url: String,
+    pub redis_key_prefix: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Chat {
     pub backend: String,  // "fake" or "real"
+    pub store: String,  // "memory" or "file"
+    pub store_path: String,
+    pub history_page_size: usize,
+    pub offline_queue_capacity: usize,
+    pub dedupe_window: u64,  // seconds
+    pub max_message_size: usize,  // bytes
+    pub rate_limit_messages: u32,
+    pub rate_limit_window: u64,  // seconds
+    pub dispatcher_workers: usize,
+    pub dispatcher_capacity: usize,
+    pub outbox_capacity: usize,
+    pub overflow_policy: String,  // "drop_oldest", "disconnect" or "backpressure"
+    pub backpressure_timeout: u64,  // milliseconds
+    pub ping_interval: u64,  // seconds
+    pub max_missed_pongs: u32,
+    pub idle_timeout: u64,  // seconds, 0 disables
+    pub typing_timeout: u64,  // seconds
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,7 +39,7 @@ impl FakeUserService {
         Ok(self.users.get(&index).ok_or(anyhow!("User index not found: {}", index))?.clone())
     }
     fn get_index(&self, user_id: &UserId) -> Result<i32> {
-        Ok(self.indices.get(user_id).ok_or(anyhow!("User ID not found: {:?}", user_id))?.clone())
+        Ok(*self.indices.get(user_id).ok_or(anyhow!("User ID not found: {:?}", user_id))?)
     }
 }
 
@@ -54,4 +54,22 @@ impl UserService for FakeUserService {
             _ => Err(anyhow!("User connections not found for index {}", index))
         }
     }
+
+    // Mirrors `get_receiver`: only the first three test users take part in conversations.
+    async fn is_member(&self, user_id: &UserId, _conversation_id: &ConversationId) -> Result<bool> {
+        Ok(matches!(self.indices.get(user_id).map(|index| *index), Some(0..=2)))
+    }
+
+    // testuser2 runs the simulated group chat.
+    async fn is_admin(&self, user_id: &UserId, _conversation_id: &ConversationId) -> Result<bool> {
+        Ok(self.indices.get(user_id).map(|index| *index) == Some(2))
+    }
+
+    async fn get_contacts(&self, user_id: &UserId) -> Result<Vec<UserId>> {
+        let index = self.get_index(user_id)?;
+        match index {
+            0..=2 => (0..=2).filter(|other| *other != index).map(|other| self.get_user_id(other)).collect(),
+            _ => Ok(vec![]),
+        }
+    }
 }
@@ -10,4 +10,9 @@ pub enum UserServiceError {
 #[async_trait::async_trait]
 pub trait UserService: Send + Sync {
     async fn get_receiver(&self, user_id: &UserId, conversation_id: &ConversationId) -> Result<Vec<UserId>>;
+    async fn is_member(&self, user_id: &UserId, conversation_id: &ConversationId) -> Result<bool>;
+    /// Admins may edit and delete anyone's messages in the conversation.
+    async fn is_admin(&self, user_id: &UserId, conversation_id: &ConversationId) -> Result<bool>;
+    /// Everyone sharing a conversation with the user, who gets to see their presence.
+    async fn get_contacts(&self, user_id: &UserId) -> Result<Vec<UserId>>;
 }